@@ -0,0 +1,4453 @@
+use std::{
+    borrow::{BorrowMut, Cow},
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::{self, Write as _},
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    StreamOwned,
+};
+
+pub enum RequestStream {
+    Tcp(TcpStream),
+    /// TLS over a plain connection, or over a TLS one to an `https://` proxy tunneling it.
+    Tls(Box<StreamOwned<ClientConnection, RequestStream>>),
+}
+
+impl RequestStream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            RequestStream::Tcp(s) => s.set_read_timeout(timeout),
+            RequestStream::Tls(s) => s.sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// Shuts the connection down cleanly, notifying the peer of the closure first under TLS.
+    pub fn close(&mut self) -> io::Result<()> {
+        match self {
+            RequestStream::Tcp(s) => s.shutdown(Shutdown::Both),
+            RequestStream::Tls(s) => {
+                s.conn.send_close_notify();
+                s.flush()?;
+                s.sock.close()
+            }
+        }
+    }
+}
+
+impl Read for RequestStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            RequestStream::Tcp(s) => s.read(buf),
+            RequestStream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for RequestStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            RequestStream::Tcp(s) => s.write(buf),
+            RequestStream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            RequestStream::Tcp(s) => s.flush(),
+            RequestStream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+/// The error a read or connect fails with once the deadline set with
+/// [`RequestContext::set_deadline`] has passed.
+#[derive(Debug)]
+struct DeadlinePassed;
+
+impl fmt::Display for DeadlinePassed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Deadline exceeded")
+    }
+}
+
+impl Error for DeadlinePassed {}
+
+impl DeadlinePassed {
+    fn error() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, DeadlinePassed)
+    }
+
+    /// Returns whether `error` is one of a read or connect cut short by the deadline.
+    fn is(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|e| e.is::<DeadlinePassed>())
+    }
+}
+
+/// Reads from a connection, arming its read timeout with what is left of the deadline before
+/// each read, so that a server trickling its response out can't stretch it past the deadline.
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Option<Instant>,
+    read_timeout: Option<Duration>,
+}
+
+impl<R: BorrowMut<BufReader<RequestStream>>> DeadlineReader<R> {
+    /// Arms the read timeout ahead of a read that will wait on the connection, returning whether
+    /// it is the deadline rather than the read timeout that bounds the wait.
+    fn arm(&mut self) -> io::Result<bool> {
+        let Some(deadline) = self.deadline else {
+            return Ok(false);
+        };
+        if !self.inner.borrow().buffer().is_empty() {
+            return Ok(false);
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(DeadlinePassed::error());
+        }
+        let bounded = self.read_timeout.is_none_or(|timeout| remaining <= timeout);
+        let timeout = self
+            .read_timeout
+            .map_or(remaining, |timeout| timeout.min(remaining));
+        self.inner
+            .borrow()
+            .get_ref()
+            .set_read_timeout(Some(timeout))?;
+        Ok(bounded)
+    }
+
+    /// Reports a read that timed out waiting for the deadline as having run past it.
+    fn check<T>(bounded: bool, result: io::Result<T>) -> io::Result<T> {
+        result.map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut if bounded => {
+                DeadlinePassed::error()
+            }
+            _ => e,
+        })
+    }
+}
+
+impl<R: BorrowMut<BufReader<RequestStream>>> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bounded = self.arm()?;
+        Self::check(bounded, self.inner.borrow_mut().read(buf))
+    }
+}
+
+impl<R: BorrowMut<BufReader<RequestStream>>> BufRead for DeadlineReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let bounded = self.arm()?;
+        Self::check(bounded, self.inner.borrow_mut().fill_buf())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.borrow_mut().consume(amount);
+    }
+}
+
+/// Header fields in the order they were received, with names lowercased.
+///
+/// A name may repeat, and single-valued lookups through [`Headers::get`] take its first value.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    pub fn get_all<'h>(&'h self, name: &str) -> impl Iterator<Item = &'h str> + 'h {
+        let name = name.to_ascii_lowercase();
+        self.fields
+            .iter()
+            .filter(move |(n, _)| *n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value for `name` after any it already has.
+    pub fn append(&mut self, name: &str, value: impl Into<String>) {
+        self.fields.push((name.to_ascii_lowercase(), value.into()));
+    }
+
+    /// Sets `value` as the only one for `name`.
+    pub fn insert(&mut self, name: &str, value: impl Into<String>) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Removes every value of `name`, returning the first one.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let name = name.to_ascii_lowercase();
+        let mut first = None;
+        self.fields.retain_mut(|(n, value)| {
+            let matches = n == &name;
+            if matches && first.is_none() {
+                first = Some(mem::take(value));
+            }
+            !matches
+        });
+        first
+    }
+
+    /// Replaces the values of every name in `other` with the ones it has there.
+    pub fn extend(&mut self, other: Headers) {
+        for (name, _) in &other.fields {
+            self.fields.retain(|(n, _)| n != name);
+        }
+        self.fields.extend(other.fields);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+pub struct CacheEntry {
+    body: Vec<u8>,
+    headers: Headers,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    stored_at: Instant,
+    max_age: Option<Duration>,
+    /// The request headers named by `Vary`, with the values they were sent with.
+    vary: Vec<(String, Option<String>)>,
+}
+
+impl CacheEntry {
+    /// Parses the `cache-control` header into whether storing is forbidden and the max-age.
+    fn cache_control(headers: &Headers) -> (bool, Option<Duration>) {
+        let mut no_store = false;
+        let mut max_age = None;
+        for directive in headers
+            .get_all("cache-control")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+        {
+            if directive.eq_ignore_ascii_case("no-store") {
+                no_store = true;
+            } else if directive.eq_ignore_ascii_case("no-cache") {
+                max_age = Some(Duration::ZERO);
+            } else if let Some(seconds) = directive.strip_prefix("max-age=") {
+                max_age = max_age.or(seconds.parse().ok().map(Duration::from_secs));
+            }
+        }
+
+        (no_store, max_age)
+    }
+
+    /// Lists the request headers a response varies by, lowercased.
+    fn vary(headers: &Headers) -> impl Iterator<Item = String> + '_ {
+        headers
+            .get_all("vary")
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+    }
+
+    /// Joins the values `request` sends for `name`, as a server would see them.
+    fn request_value(request: &Headers, name: &str) -> Option<String> {
+        let values = request.get_all(name).collect::<Vec<_>>();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    fn is_cacheable(status: u16, headers: &Headers) -> bool {
+        let (no_store, max_age) = Self::cache_control(headers);
+        // A response varying by `*` depends on more than the request, so it never matches one.
+        status == 200
+            && !no_store
+            && Self::vary(headers).all(|name| name != "*")
+            && (max_age.is_some()
+                || headers.contains_key("etag")
+                || headers.contains_key("last-modified"))
+    }
+
+    /// Stores the response to a request sent with the `request` headers.
+    fn new(headers: Headers, body: Vec<u8>, request: &Headers) -> Self {
+        let (_, max_age) = Self::cache_control(&headers);
+        let vary = Self::vary(&headers)
+            .map(|name| {
+                let value = Self::request_value(request, &name);
+                (name, value)
+            })
+            .collect();
+        Self {
+            etag: headers.get("etag").map(String::from),
+            last_modified: headers.get("last-modified").map(String::from),
+            body,
+            headers,
+            stored_at: Instant::now(),
+            max_age,
+            vary,
+        }
+    }
+
+    /// Returns whether this response also answers a request sent with the `request` headers.
+    fn matches(&self, request: &Headers) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| Self::request_value(request, name) == *value)
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.max_age
+            .is_some_and(|max_age| self.stored_at.elapsed() < max_age)
+    }
+
+    fn to_response(&self) -> Response<'static> {
+        Response {
+            status: 200,
+            headers: self.headers.clone(),
+            body: Box::new(io::Cursor::new(self.body.clone())),
+        }
+    }
+}
+
+/// Parses an HTTP date such as `Sun, 06 Nov 1994 08:49:37 GMT`, also accepting the dashed
+/// `06-Nov-1994` form common in cookie expiry dates.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+
+    let (_weekday, date) = date.split_once(',')?;
+    let mut fields = date.split([' ', '-']).filter(|field| !field.is_empty());
+    let day: u64 = fields.next()?.parse().ok()?;
+    let month = fields.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let mut year: u64 = fields.next()?.parse().ok()?;
+    if year < 100 {
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+
+    let mut time = fields.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    // Days since the epoch of a proleptic Gregorian date, counting years from March.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let day_of_era =
+        (y % 400) * 365 + (y % 400) / 4 - (y % 400) / 100 + (153 * m + 2) / 5 + day - 1;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+
+    let secs = days * 86_400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+struct Cookie {
+    name: String,
+    value: String,
+    path: String,
+    /// Whether the cookie was set without a `Domain` and only goes back to the exact host.
+    host_only: bool,
+    secure: bool,
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    /// Parses a `Set-Cookie` value received from `host` for a request to `path`, returning the
+    /// domain it applies to along with it.
+    fn parse(set_cookie: &str, host: &str, path: &str) -> Option<(String, Self)> {
+        let mut attributes = set_cookie.split(';');
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        // Cookies without a `Path` apply to the directory of the path that set them.
+        let default_path = match path.rfind('/') {
+            Some(0) | None => "/",
+            Some(i) => &path[..i],
+        };
+
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+            path: default_path.to_string(),
+            host_only: true,
+            secure: false,
+            expires: None,
+        };
+        let mut domain = host.to_string();
+        let mut max_age = None;
+
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let value = value.trim_start_matches('.').to_ascii_lowercase();
+                    if !Self::domain_matches(host, &value) {
+                        return None;
+                    }
+
+                    domain = value;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" => {
+                    cookie.expires = cookie.expires.or_else(|| parse_http_date(value));
+                }
+                "secure" => cookie.secure = true,
+                _ => {}
+            }
+        }
+
+        // `Max-Age` takes precedence over `Expires`, and a non-positive one expires right away.
+        if let Some(max_age) = max_age {
+            cookie.expires = Some(match u64::try_from(max_age) {
+                Ok(secs) if secs > 0 => SystemTime::now() + Duration::from_secs(secs),
+                _ => UNIX_EPOCH,
+            });
+        }
+
+        Some((domain, cookie))
+    }
+
+    fn domain_matches(host: &str, domain: &str) -> bool {
+        host == domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    }
+
+    fn path_matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.path).is_some_and(|rest| {
+            rest.is_empty() || rest.starts_with('/') || self.path.ends_with('/')
+        })
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Cookies received from servers, grouped by the domain they apply to.
+#[derive(Default)]
+struct CookieJar {
+    cookies: HashMap<String, Vec<Cookie>>,
+}
+
+impl CookieJar {
+    /// Stores the cookies of the `Set-Cookie` values received from `host`.
+    fn store<'v>(&mut self, host: &str, path: &str, set_cookies: impl Iterator<Item = &'v str>) {
+        for set_cookie in set_cookies {
+            let Some((domain, cookie)) = Cookie::parse(set_cookie, host, path) else {
+                continue;
+            };
+
+            let cookies = self.cookies.entry(domain).or_default();
+            cookies.retain(|c| c.name != cookie.name || c.path != cookie.path);
+            if !cookie.is_expired(SystemTime::now()) {
+                cookies.push(cookie);
+            }
+        }
+    }
+
+    /// Builds the `Cookie` header value for a request to `path` on `host`, dropping any cookie
+    /// that expired since it was stored.
+    fn header(&mut self, host: &str, path: &str, secure: bool) -> Option<String> {
+        let now = SystemTime::now();
+        let mut pairs = Vec::new();
+        for (domain, cookies) in &mut self.cookies {
+            cookies.retain(|cookie| !cookie.is_expired(now));
+            let matching = cookies.iter().filter(|cookie| {
+                (if cookie.host_only {
+                    host == domain
+                } else {
+                    Cookie::domain_matches(host, domain)
+                }) && cookie.path_matches(path)
+                    && (secure || !cookie.secure)
+            });
+            pairs.extend(matching.map(|cookie| format!("{}={}", cookie.name, cookie.value)));
+        }
+
+        (!pairs.is_empty()).then(|| pairs.join("; "))
+    }
+}
+
+enum Framing {
+    Length {
+        remaining: usize,
+        length: usize,
+    },
+    Chunked {
+        remaining: usize,
+        done: bool,
+    },
+    /// The body runs until the server closes the connection.
+    UntilClose,
+}
+
+/// Reads exactly one message body off a connection, as delimited by its framing.
+///
+/// The underlying reader is left positioned at the start of the next response, even if the body
+/// is dropped before being read to the end.
+pub struct BodyReader<R: BufRead> {
+    inner: R,
+    framing: Framing,
+}
+
+impl<R: BufRead> BodyReader<R> {
+    fn with_length(inner: R, length: usize) -> Self {
+        Self {
+            inner,
+            framing: Framing::Length {
+                remaining: length,
+                length,
+            },
+        }
+    }
+
+    fn until_close(inner: R) -> Self {
+        Self {
+            inner,
+            framing: Framing::UntilClose,
+        }
+    }
+
+    fn chunked(inner: R) -> Self {
+        Self {
+            inner,
+            framing: Framing::Chunked {
+                remaining: 0,
+                done: false,
+            },
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+        Ok(line)
+    }
+}
+
+impl<R: BufRead> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = match self.framing {
+            // Servers ending the body by closing the connection don't always close TLS cleanly.
+            Framing::UntilClose => {
+                return match self.inner.read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                    result => result,
+                };
+            }
+            Framing::Length { remaining, .. } => remaining,
+            Framing::Chunked { done: true, .. } => 0,
+            Framing::Chunked { remaining: 0, .. } => {
+                let line = self.read_line()?;
+                let size = line.split(';').next().unwrap_or_default().trim();
+                let size = usize::from_str_radix(size, 16)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                if size == 0 {
+                    // Discard any trailer fields up to the final empty line, or the end of the
+                    // stream, without reading into whatever follows it.
+                    loop {
+                        let line = self.read_line()?;
+                        if line.trim_end_matches(['\r', '\n']).is_empty() {
+                            break;
+                        }
+                    }
+                }
+
+                self.framing = Framing::Chunked {
+                    remaining: size,
+                    done: size == 0,
+                };
+                size
+            }
+            Framing::Chunked { remaining, .. } => remaining,
+        };
+
+        if remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max = buf.len().min(remaining);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            if let Framing::Length { remaining, length } = self.framing {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Body ended after {} of {length} bytes", length - remaining),
+                ));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        match &mut self.framing {
+            Framing::UntilClose => unreachable!(),
+            Framing::Length { remaining, .. } => *remaining -= n,
+            Framing::Chunked { remaining, .. } => {
+                *remaining -= n;
+                if *remaining == 0 {
+                    let terminator = self.read_line()?;
+                    if !terminator.trim_end_matches(['\r', '\n']).is_empty() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Chunk is longer than its size",
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> Drop for BodyReader<R> {
+    fn drop(&mut self) {
+        // The connection of a body running until close can't be reused, so it isn't drained.
+        if !matches!(self.framing, Framing::UntilClose) {
+            let _ = io::copy(self, &mut io::sink());
+        }
+    }
+}
+
+/// Bounds on how much a compressed body may expand while being decoded.
+#[derive(Clone, Copy, Debug, Default)]
+struct DecodeLimits {
+    max_size: Option<u64>,
+    max_ratio: Option<u64>,
+}
+
+impl DecodeLimits {
+    /// Decoded bytes allowed before the ratio is checked, so tiny bodies aren't judged by the
+    /// fixed overhead of their coding.
+    const RATIO_GRACE: u64 = 64 * 1024;
+
+    fn check(&self, decoded: u64, encoded: u64) -> io::Result<()> {
+        if self.max_size.is_some_and(|max| decoded > max) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Decoded body exceeds {} bytes", self.max_size.unwrap()),
+            ));
+        }
+
+        if decoded > Self::RATIO_GRACE
+            && self
+                .max_ratio
+                .is_some_and(|max| decoded > encoded.saturating_mul(max))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Decoded body expands more than {}x",
+                    self.max_ratio.unwrap()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts the bytes read through it into a counter shared with a `LimitedDecoder`.
+struct CountingReader<R: Read> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Fails the decoded stream as soon as it grows past `limits`.
+struct LimitedDecoder<'a> {
+    inner: Box<dyn Read + 'a>,
+    encoded: Rc<Cell<u64>>,
+    decoded: u64,
+    limits: DecodeLimits,
+}
+
+impl Read for LimitedDecoder<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.decoded += n as u64;
+        self.limits.check(self.decoded, self.encoded.get())?;
+        Ok(n)
+    }
+}
+
+/// The bytes of a body read before its connection was lost, followed by the error it was lost
+/// with.
+struct Truncated {
+    read: io::Cursor<Vec<u8>>,
+    error: Option<io::Error>,
+}
+
+impl Read for Truncated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read.read(buf)?;
+        if n > 0 || buf.is_empty() {
+            return Ok(n);
+        }
+
+        Err(self
+            .error
+            .take()
+            .unwrap_or_else(|| io::ErrorKind::UnexpectedEof.into()))
+    }
+}
+
+/// Fails the body as soon as it grows past `max` bytes, whether it was encoded or not.
+struct SizeLimited<'a> {
+    inner: Box<dyn Read + 'a>,
+    read: u64,
+    max: u64,
+}
+
+impl Read for SizeLimited<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read > self.max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Body exceeds {} bytes", self.max),
+            ));
+        }
+
+        Ok(n)
+    }
+}
+
+/// Prints how long reading the body took to stderr once it is dropped, for `--trace`.
+struct TracedBody<'a> {
+    inner: Box<dyn Read + 'a>,
+    addr: (String, u16),
+    started: Instant,
+    read: u64,
+}
+
+impl Read for TracedBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl Drop for TracedBody<'_> {
+    fn drop(&mut self) {
+        let (host, port) = &self.addr;
+        eprintln!(
+            "* Response body from {host}:{port}: {} bytes in {:.1?}",
+            self.read,
+            self.started.elapsed()
+        );
+    }
+}
+
+/// The phases of a request that are timed into [`Timings`].
+#[derive(Clone, Copy)]
+enum Phase {
+    Resolve,
+    Connect,
+    TlsHandshake,
+    RequestWrite,
+    ResponseHead,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Resolve => "Resolve",
+            Phase::Connect => "Connect",
+            Phase::TlsHandshake => "TLS handshake",
+            Phase::RequestWrite => "Request write",
+            Phase::ResponseHead => "Response head",
+        }
+    }
+}
+
+/// Time spent in each phase of the requests made through a [`RequestContext`], added up since
+/// the last call to [`RequestContext::take_timings`].
+///
+/// Requests over a pooled connection spend nothing on resolving, connecting or the handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub resolve: Duration,
+    pub connect: Duration,
+    pub tls_handshake: Duration,
+    pub request_write: Duration,
+    /// From the request being sent until its response head is read, the time to first byte.
+    pub response_head: Duration,
+}
+
+impl Timings {
+    fn add(&mut self, phase: Phase, elapsed: Duration) {
+        let total = match phase {
+            Phase::Resolve => &mut self.resolve,
+            Phase::Connect => &mut self.connect,
+            Phase::TlsHandshake => &mut self.tls_handshake,
+            Phase::RequestWrite => &mut self.request_write,
+            Phase::ResponseHead => &mut self.response_head,
+        };
+        *total += elapsed;
+    }
+}
+
+/// Decodes standard base64, ignoring whitespace and stopping at the first `=` padding.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+
+        acc = (acc << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Encodes `bytes` as padded standard base64.
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(char::from(ALPHABET[index as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// A proxy that requests are sent through, as given by `--proxy` or the `*_proxy` environment
+/// variables.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    pub addr: (String, u16),
+    /// Whether the proxy itself is spoken to over TLS, as an `https://` proxy is.
+    pub tls: bool,
+    /// The `user:password` of the proxy URL, sent along as Basic credentials.
+    pub credentials: Option<String>,
+}
+
+impl Proxy {
+    /// Parses a proxy given as `host:port` or as an `http://` or `https://` URL, which may name
+    /// `user:password@` to authenticate with.
+    pub fn parse(proxy: &str) -> Result<Self, UrlParseError> {
+        let (scheme, rest) = proxy.split_once("://").unwrap_or(("http", proxy));
+        let scheme = scheme.to_ascii_lowercase();
+        if !matches!(scheme.as_str(), "http" | "https") {
+            return Err(UrlParseError::UnsupportedScheme(scheme));
+        }
+
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let (userinfo, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let (Url::Http { addr, .. } | Url::Https { addr, .. }) =
+            Url::new(&format!("{scheme}://{host}/"))?
+        else {
+            unreachable!("http and https URLs parse as such");
+        };
+
+        let credentials = userinfo.map(|userinfo| {
+            let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            format!(
+                "{}:{}",
+                Url::percent_decode(user),
+                Url::percent_decode(password)
+            )
+        });
+
+        Ok(Self {
+            addr,
+            tls: scheme == "https",
+            credentials,
+        })
+    }
+
+    /// Returns the `Proxy-Authorization` to send along with requests, if there are credentials.
+    fn authorization(&self) -> Option<String> {
+        let credentials = self.credentials.as_ref()?;
+        Some(format!("Basic {}", encode_base64(credentials.as_bytes())))
+    }
+}
+
+/// Extracts the `CERTIFICATE` blocks of a PEM bundle, skipping any other kind of block.
+fn parse_pem_certificates(pem: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find(BEGIN) {
+        rest = &rest[start + BEGIN.len()..];
+        let end = rest.find(END).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Unterminated PEM certificate")
+        })?;
+
+        let der = decode_base64(&rest[..end]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid base64 in PEM certificate",
+            )
+        })?;
+        certificates.push(CertificateDer::from(der));
+        rest = &rest[end + END.len()..];
+    }
+
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No PEM certificates found",
+        ));
+    }
+
+    Ok(certificates)
+}
+
+fn default_root_store() -> RootCertStore {
+    webpki_roots::TLS_SERVER_ROOTS.iter().cloned().collect()
+}
+
+/// Accepts any server certificate while still checking the handshake signatures made with it.
+///
+/// This is only meant for testing against local servers with self-signed certificates, as it
+/// leaves connections open to impersonation.
+#[derive(Debug)]
+struct NoCertificateVerification(CryptoProvider);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Identifies a pooled connection by the scheme spoken over it as well as its address, since a
+/// plaintext and a TLS connection to the same port can't stand in for each other.
+type PoolKey = (&'static str, (String, u16));
+
+#[derive(Default)]
+pub struct RequestContext {
+    inner: HashMap<PoolKey, BufReader<RequestStream>>,
+    /// Keys of the pooled connections, from least to most recently used.
+    recency: VecDeque<PoolKey>,
+    max_connections: Option<usize>,
+    connect_attempts: Option<usize>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    http_proxy: Option<Proxy>,
+    https_proxy: Option<Proxy>,
+    /// Hosts reached directly even with a proxy set, lowercased and without a leading dot.
+    no_proxy: Vec<String>,
+    cache: HashMap<Url, CacheEntry>,
+    root_store: Option<Arc<RootCertStore>>,
+    retry_budget: Option<usize>,
+    retries: Cell<usize>,
+    verbose: bool,
+    trace: bool,
+    timings: Cell<Timings>,
+    decode_limits: DecodeLimits,
+    max_body_size: Option<u64>,
+    max_header_size: Option<usize>,
+    user_agent: Option<String>,
+    server_name: Option<String>,
+    insecure: bool,
+    cookies: CookieJar,
+}
+
+impl RequestContext {
+    const DEFAULT_MAX_CONNECTIONS: usize = 16;
+    const DEFAULT_CONNECT_ATTEMPTS: usize = 3;
+    const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+
+    pub fn builder() -> RequestContextBuilder {
+        RequestContextBuilder::default()
+    }
+
+    /// Trusts exactly the certificates in `root_store` instead of the bundled webpki roots.
+    pub fn set_root_store(&mut self, root_store: RootCertStore) {
+        self.root_store = Some(Arc::new(root_store));
+    }
+
+    /// Trusts the certificates in the PEM file at `path` on top of the current trust anchors,
+    /// returning how many were added.
+    pub fn add_pem_roots(&mut self, path: &Path) -> io::Result<usize> {
+        let certificates = parse_pem_certificates(&fs::read_to_string(path)?)?;
+        let mut root_store = match self.root_store.take() {
+            Some(root_store) => Arc::unwrap_or_clone(root_store),
+            None => default_root_store(),
+        };
+
+        let count = certificates.len();
+        for certificate in certificates {
+            root_store
+                .add(certificate)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+
+        self.root_store = Some(Arc::new(root_store));
+        Ok(count)
+    }
+
+    /// Skips verifying server certificates, for testing against servers with self-signed ones.
+    ///
+    /// This must never be enabled outside of testing, since anyone on the network can then
+    /// impersonate any server.
+    pub fn set_insecure(&mut self, insecure: bool) {
+        self.insecure = insecure;
+    }
+
+    /// Prints the request and response heads exchanged with servers to stderr.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Prints how long each phase of a request takes to stderr: resolution, connecting, the TLS
+    /// handshake, writing the request and reading the response head and body.
+    ///
+    /// A request over a pooled connection skips the first three and says so instead.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Returns the time spent in each phase of the requests made since the last call, and starts
+    /// adding up from zero again.
+    pub fn take_timings(&mut self) -> Timings {
+        self.timings.take()
+    }
+
+    /// Adds `elapsed` to the time spent in `phase`, also printing it to stderr when tracing.
+    fn record(&self, phase: Phase, addr: &(String, u16), elapsed: Duration, succeeded: bool) {
+        let mut timings = self.timings.get();
+        timings.add(phase, elapsed);
+        self.timings.set(timings);
+
+        if self.trace {
+            let (name, (host, port)) = (phase.name(), addr);
+            let outcome = if succeeded { "" } else { "failed after " };
+            eprintln!("* {name} {host}:{port}: {outcome}{elapsed:.1?}");
+        }
+    }
+
+    /// Runs one phase of a request, timing it with [`RequestContext::record`].
+    fn trace_phase<T>(
+        &self,
+        phase: Phase,
+        addr: &(String, u16),
+        run: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<T> {
+        let started = Instant::now();
+        let result = run();
+        self.record(phase, addr, started.elapsed(), result.is_ok());
+        result
+    }
+
+    /// Caps the number of retries shared by every request made through this context.
+    pub fn set_retry_budget(&mut self, budget: usize) {
+        self.retry_budget = Some(budget);
+    }
+
+    /// Aborts decoding a compressed body once it grows past `max` bytes.
+    pub fn set_max_decoded_size(&mut self, max: u64) {
+        self.decode_limits.max_size = Some(max);
+    }
+
+    /// Aborts decoding a compressed body once it grows past `max` times the size it was sent
+    /// with.
+    pub fn set_max_decode_ratio(&mut self, max: u64) {
+        self.decode_limits.max_ratio = Some(max);
+    }
+
+    /// Aborts reading a body once it grows past `max` bytes, after any decoding.
+    pub fn set_max_body_size(&mut self, max: u64) {
+        self.max_body_size = Some(max);
+    }
+
+    /// Rejects responses whose status line and headers take more than `max` bytes, 64 KiB if
+    /// unset.
+    pub fn set_max_header_size(&mut self, max: usize) {
+        self.max_header_size = Some(max);
+    }
+
+    /// Presents `server_name` in TLS handshakes, and verifies certificates against it, instead of
+    /// the host of each URL, to go with an overridden [`RequestOptions::host`].
+    pub fn set_server_name(&mut self, server_name: Option<String>) {
+        self.server_name = server_name;
+    }
+
+    /// Sends `user_agent` as the `User-Agent` of every request instead of `vanadium/<version>`.
+    pub fn set_user_agent(&mut self, user_agent: String) {
+        self.user_agent = Some(user_agent);
+    }
+
+    /// Caps the number of connections kept open at once, closing the least recently used one to
+    /// make room for a new one.
+    pub fn set_max_connections(&mut self, max: usize) {
+        let max = max.max(1);
+        self.max_connections = Some(max);
+        while self.inner.len() > max {
+            self.evict();
+        }
+    }
+
+    /// Sets how many times opening a connection is attempted before giving up, three if unset.
+    pub fn set_connect_attempts(&mut self, attempts: usize) {
+        self.connect_attempts = Some(attempts.max(1));
+    }
+
+    /// Gives up on resolving a host, or on connecting to one of its addresses, after `timeout`.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Gives up on reads that wait longer than `timeout`, on the pooled connections as well as on
+    /// the ones opened later.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        for reader in self.inner.values() {
+            reader.get_ref().set_read_timeout(timeout)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails lookups, connects and reads with [`io::ErrorKind::TimedOut`] once `deadline` has
+    /// passed, including the backoff between connect attempts as well as every read of a response.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        self.deadline = deadline;
+        if deadline.is_none() {
+            // Reads under the deadline leave shorter timeouts behind on the pooled connections.
+            self.set_read_timeout(self.read_timeout)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the time left before the deadline, failing if it has already passed.
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        let Some(deadline) = self.deadline else {
+            return Ok(None);
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(DeadlinePassed::error());
+        }
+        Ok(Some(remaining))
+    }
+
+    /// Shortens `timeout` to the time left before the deadline.
+    fn within_deadline(&self, timeout: Option<Duration>) -> io::Result<Option<Duration>> {
+        Ok(match (timeout, self.remaining()?) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        })
+    }
+
+    /// Returns the pooled connection for `url` like [`RequestContext::reader`], bounding each of
+    /// its reads by the deadline.
+    fn timed_reader(
+        &mut self,
+        url: &Url,
+    ) -> io::Result<DeadlineReader<&mut BufReader<RequestStream>>> {
+        let (deadline, read_timeout) = (self.deadline, self.read_timeout);
+        Ok(DeadlineReader {
+            inner: self.reader(url)?,
+            deadline,
+            read_timeout,
+        })
+    }
+
+    /// Sends plain `http` requests through `proxy`.
+    pub fn set_http_proxy(&mut self, proxy: Proxy) {
+        self.http_proxy = Some(proxy);
+    }
+
+    /// Tunnels `https` and `gemini` connections through `proxy`.
+    pub fn set_https_proxy(&mut self, proxy: Proxy) {
+        self.https_proxy = Some(proxy);
+    }
+
+    /// Reaches the hosts in the comma separated `hosts` directly rather than through a proxy,
+    /// spelt like `NO_PROXY`: each also covers its subdomains, may be limited to a `:port`, and
+    /// `*` stands for every host.
+    pub fn set_no_proxy(&mut self, hosts: &str) {
+        self.no_proxy = hosts
+            .split(',')
+            .map(|host| host.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+    }
+
+    /// Returns the proxy that `url` is fetched through, if any.
+    fn proxy(&self, url: &Url) -> Option<&Proxy> {
+        let (proxy, (host, port)) = match url {
+            Url::Http { addr, .. } => (self.http_proxy.as_ref()?, addr),
+            Url::Https { addr, .. } | Url::Gemini { addr, .. } => {
+                (self.https_proxy.as_ref()?, addr)
+            }
+            _ => return None,
+        };
+
+        let host = host.to_ascii_lowercase();
+        let bypassed = self.no_proxy.iter().any(|entry| {
+            let (name, entry_port) = match entry.rsplit_once(':') {
+                Some((name, entry_port)) => match entry_port.parse::<u16>() {
+                    Ok(entry_port) => (name, Some(entry_port)),
+                    Err(_) => (entry.as_str(), None),
+                },
+                None => (entry.as_str(), None),
+            };
+            let host_matches = name == "*"
+                || host == name
+                || host
+                    .strip_suffix(name)
+                    .is_some_and(|rest| rest.ends_with('.'));
+            host_matches && entry_port.is_none_or(|entry_port| entry_port == *port)
+        });
+
+        (!bypassed).then_some(proxy)
+    }
+
+    /// Bounds `body` by the maximum body size, if there is one.
+    fn limit_body<'a>(max: Option<u64>, body: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        match max {
+            Some(max) => Box::new(SizeLimited {
+                inner: body,
+                read: 0,
+                max,
+            }),
+            None => body,
+        }
+    }
+
+    /// Wraps `body` to report how long it took to read when `trace` is set.
+    fn trace_body<'a>(trace: bool, url: &Url, body: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        match Self::addr(url) {
+            Ok(addr) if trace => Box::new(TracedBody {
+                inner: body,
+                addr: addr.clone(),
+                started: Instant::now(),
+                read: 0,
+            }),
+            _ => body,
+        }
+    }
+
+    /// Returns the proxy that requests for `url` are sent to instead of its own host, which is
+    /// only ever done for plain `http`, the others being tunneled.
+    fn forward_proxy(&self, url: &Url) -> Option<&Proxy> {
+        self.proxy(url).filter(|_| matches!(url, Url::Http { .. }))
+    }
+
+    /// Closes the least recently used pooled connection.
+    fn evict(&mut self) {
+        if let Some(mut reader) = self
+            .recency
+            .pop_front()
+            .and_then(|key| self.inner.remove(&key))
+        {
+            let _ = reader.get_mut().close();
+        }
+    }
+
+    /// Takes the connection with `key` out of the pool, without closing it.
+    fn take_connection(&mut self, key: &PoolKey) -> Option<BufReader<RequestStream>> {
+        self.recency.retain(|k| k != key);
+        self.inner.remove(key)
+    }
+
+    /// Spends one retry from the shared budget, returning whether there was any left.
+    fn take_retry(&self) -> bool {
+        if self
+            .retry_budget
+            .is_some_and(|budget| self.retries.get() >= budget)
+        {
+            return false;
+        }
+
+        self.retries.set(self.retries.get() + 1);
+        true
+    }
+
+    /// Resolves `addr`, giving up after the connect timeout, and wraps any failure in a
+    /// [`ResolveError`].
+    fn resolve(&self, addr: &(String, u16)) -> io::Result<Vec<SocketAddr>> {
+        let lookup = |addr: (String, u16)| {
+            let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No addresses found",
+                ));
+            }
+            Ok(addrs)
+        };
+
+        let result = match self.within_deadline(self.connect_timeout)? {
+            Some(timeout) => {
+                // A lookup can't be cancelled, so one that is too slow is left to finish in the
+                // background.
+                let (sender, receiver) = mpsc::channel();
+                let target = addr.clone();
+                thread::spawn(move || sender.send(lookup(target)));
+                receiver.recv_timeout(timeout).unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Resolution timed out",
+                    ))
+                })
+            }
+            None => lookup(addr.clone()),
+        };
+
+        // The kind of the failure is kept, so that callers can still tell lookups that timed out.
+        result.map_err(|source| {
+            io::Error::new(
+                source.kind(),
+                ResolveError {
+                    host: addr.0.clone(),
+                    source,
+                },
+            )
+        })
+    }
+
+    /// Tries each of the resolved `addrs` of `addr` in order, returning the first connection
+    /// made, or an error listing why each of them failed.
+    fn connect_any(&self, addr: &(String, u16), addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        let mut failures = Vec::with_capacity(addrs.len());
+        let mut kind = io::ErrorKind::NotFound;
+        for socket_addr in addrs {
+            let result = match self.within_deadline(self.connect_timeout)? {
+                Some(timeout) => TcpStream::connect_timeout(socket_addr, timeout),
+                None => TcpStream::connect(socket_addr),
+            };
+
+            match result {
+                Ok(stream) => {
+                    stream.set_read_timeout(self.read_timeout)?;
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    kind = e.kind();
+                    failures.push(format!("{socket_addr} ({e})"));
+                }
+            }
+        }
+
+        // The kind of the last failure is kept, so that callers can still tell refused
+        // connections from timed out ones.
+        Err(io::Error::new(
+            kind,
+            format!(
+                "Could not connect to {}:{}, tried {}",
+                addr.0,
+                addr.1,
+                failures.join(", ")
+            ),
+        ))
+    }
+
+    /// Connects to `addr`, telling a host that doesn't resolve apart from one that can't be
+    /// reached by wrapping the former in a [`ResolveError`].
+    ///
+    /// Failing connections are retried with exponential backoff, but resolution failures are
+    /// taken as permanent.
+    fn connect(&self, addr: &(String, u16)) -> io::Result<TcpStream> {
+        const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+        let addrs = self.trace_phase(Phase::Resolve, addr, || self.resolve(addr))?;
+        let attempts = self
+            .connect_attempts
+            .unwrap_or(Self::DEFAULT_CONNECT_ATTEMPTS);
+        let mut backoff = INITIAL_BACKOFF;
+        for _ in 1..attempts {
+            match self.trace_phase(Phase::Connect, addr, || self.connect_any(addr, &addrs)) {
+                Ok(stream) => return Ok(stream),
+                Err(e) if !self.take_retry() => return Err(e),
+                Err(e) if self.verbose => eprintln!("* {e}, retrying"),
+                Err(_) => {}
+            }
+
+            // Backing off counts against the deadline, without ever sleeping past it.
+            let remaining = self.remaining()?;
+            thread::sleep(remaining.map_or(backoff, |remaining| backoff.min(remaining)));
+            backoff *= 2;
+        }
+
+        self.trace_phase(Phase::Connect, addr, || self.connect_any(addr, &addrs))
+    }
+
+    /// Connects to `proxy`, over TLS if it asks for it.
+    fn connect_proxy(&self, proxy: &Proxy) -> io::Result<RequestStream> {
+        let s = RequestStream::Tcp(self.connect(&proxy.addr)?);
+        if proxy.tls {
+            self.handshake(s, &proxy.addr.0, &proxy.addr)
+        } else {
+            Ok(s)
+        }
+    }
+
+    /// Connects to `addr` through a `CONNECT` tunnel opened by `proxy`.
+    fn tunnel(&self, proxy: &Proxy, addr: &(String, u16)) -> io::Result<RequestStream> {
+        let mut s = self.connect_proxy(proxy)?;
+        let (host, port) = addr;
+        let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some(authorization) = proxy.authorization() {
+            write!(&mut request, "Proxy-Authorization: {authorization}\r\n").unwrap();
+        }
+        request.push_str("\r\n");
+        if self.verbose {
+            eprintln!("* Tunneling through {}:{}", proxy.addr.0, proxy.addr.1);
+        }
+        s.write_all(request.as_bytes())?;
+        s.set_read_timeout(self.within_deadline(self.read_timeout)?)?;
+
+        // The proxy says nothing more until the client starts the handshake, so nothing the
+        // reader buffers past the head is lost.
+        let mut reader = BufReader::new(&mut s);
+        let mut statusline = String::new();
+        reader.read_line(&mut statusline)?;
+        let status = Url::parse_status_line(&statusline)?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                break;
+            }
+        }
+
+        drop(reader);
+        if !(200..300).contains(&status) {
+            return Err(io::Error::other(format!(
+                "Proxy refused to tunnel to {host}:{port} with status {status}"
+            )));
+        }
+
+        Ok(s)
+    }
+
+    /// Starts a TLS session with the server `name` over `s`, a connection to `addr`.
+    fn handshake(
+        &self,
+        s: RequestStream,
+        name: &str,
+        addr: &(String, u16),
+    ) -> io::Result<RequestStream> {
+        let config = if self.insecure {
+            let verifier = NoCertificateVerification(crypto::aws_lc_rs::default_provider());
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        } else {
+            let root_store = match &self.root_store {
+                Some(root_store) => Arc::clone(root_store),
+                None => Arc::new(default_root_store()),
+            };
+            ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth()
+        };
+
+        let hostname = ServerName::try_from(name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let client = ClientConnection::new(Arc::new(config), hostname).map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(client, s);
+
+        // The handshake is completed up front rather than on the first write, so that its time
+        // isn't counted as writing the request.
+        self.trace_phase(Phase::TlsHandshake, addr, || {
+            while stream.conn.is_handshaking() {
+                let timeout = self.within_deadline(self.read_timeout)?;
+                stream.sock.set_read_timeout(timeout)?;
+                stream.conn.complete_io(&mut stream.sock)?;
+            }
+            Ok(())
+        })?;
+        Ok(RequestStream::Tls(Box::new(stream)))
+    }
+
+    fn build_reader(&self, url: &Url) -> io::Result<BufReader<RequestStream>> {
+        match url {
+            Url::Http { addr, .. } => {
+                let s = match self.proxy(url) {
+                    Some(proxy) => self.connect_proxy(proxy)?,
+                    None => RequestStream::Tcp(self.connect(addr)?),
+                };
+                Ok(BufReader::new(s))
+            }
+            Url::Https { addr, .. } | Url::Gemini { addr, .. } => {
+                let s = match self.proxy(url) {
+                    Some(proxy) => self.tunnel(proxy, addr)?,
+                    None => RequestStream::Tcp(self.connect(addr)?),
+                };
+                let name = self.server_name.as_ref().unwrap_or(&addr.0);
+                Ok(BufReader::new(self.handshake(s, name, addr)?))
+            }
+            _ => Err(Self::not_networked(url)),
+        }
+    }
+
+    /// Returns the address `url` is fetched from, or an error for URLs that are read locally.
+    fn addr(url: &Url) -> io::Result<&(String, u16)> {
+        match url {
+            Url::Http { addr, .. } | Url::Https { addr, .. } | Url::Gemini { addr, .. } => Ok(addr),
+            _ => Err(Self::not_networked(url)),
+        }
+    }
+
+    /// Returns the key of the pooled connection `url` is fetched over.
+    fn pool_key(url: &Url) -> io::Result<PoolKey> {
+        Ok((url.scheme(), Self::addr(url)?.clone()))
+    }
+
+    fn not_networked(url: &Url) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{url} is not fetched over a connection"),
+        )
+    }
+
+    pub fn stream(&mut self, url: &Url) -> io::Result<&mut RequestStream> {
+        Ok(self.reader(url)?.get_mut())
+    }
+
+    /// Returns the pooled connection for `url`, opening it first if there is none.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] for `file`, `data` and `about` URLs, which are
+    /// not fetched over a connection.
+    pub fn reader(&mut self, url: &Url) -> io::Result<&mut BufReader<RequestStream>> {
+        let key = Self::pool_key(url)?;
+
+        if self.inner.contains_key(&key) {
+            self.recency.retain(|k| *k != key);
+        } else {
+            let reader = self.build_reader(url)?;
+            let max = self
+                .max_connections
+                .unwrap_or(Self::DEFAULT_MAX_CONNECTIONS);
+            while self.inner.len() >= max {
+                self.evict();
+            }
+            self.inner.insert(key.clone(), reader);
+        }
+
+        self.recency.push_back(key.clone());
+        Ok(self
+            .inner
+            .get_mut(&key)
+            .expect("connection was just pooled"))
+    }
+
+    /// Hands out the connection to read a response body from, taking it out of the pool first if
+    /// it is closing after this response.
+    fn body_reader(&mut self, url: &Url, closing: bool) -> io::Result<Box<dyn BufRead + '_>> {
+        let key = Self::pool_key(url)?;
+        if closing {
+            if let Some(reader) = self.take_connection(&key) {
+                return Ok(Box::new(DeadlineReader {
+                    inner: reader,
+                    deadline: self.deadline,
+                    read_timeout: self.read_timeout,
+                }));
+            }
+        }
+
+        Ok(Box::new(self.timed_reader(url)?))
+    }
+
+    /// Returns whether there is a pooled connection that would be reused for `url`.
+    pub fn is_connected(&self, url: &Url) -> bool {
+        Self::pool_key(url).is_ok_and(|key| self.inner.contains_key(&key))
+    }
+
+    /// Closes the pooled connection for `url`, so that the next request opens a new one.
+    pub fn disconnect(&mut self, url: &Url) {
+        if let Some(mut reader) = Self::pool_key(url)
+            .ok()
+            .and_then(|key| self.take_connection(&key))
+        {
+            let _ = reader.get_mut().close();
+        }
+    }
+}
+
+/// Configures a [`RequestContext`] in one go, starting from the defaults of
+/// [`RequestContext::default`].
+#[derive(Default)]
+pub struct RequestContextBuilder {
+    ctx: RequestContext,
+}
+
+impl RequestContextBuilder {
+    /// See [`RequestContext::set_read_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.ctx.read_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`RequestContext::set_max_body_size`].
+    pub fn max_body_size(mut self, max: u64) -> Self {
+        self.ctx.set_max_body_size(max);
+        self
+    }
+
+    /// See [`RequestContext::set_user_agent`].
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.ctx.set_user_agent(user_agent.into());
+        self
+    }
+
+    /// See [`RequestContext::set_insecure`].
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.ctx.set_insecure(insecure);
+        self
+    }
+
+    /// See [`RequestContext::set_max_connections`].
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.ctx.set_max_connections(max);
+        self
+    }
+
+    pub fn build(self) -> RequestContext {
+        self.ctx
+    }
+}
+
+impl Drop for RequestContext {
+    fn drop(&mut self) {
+        for reader in self.inner.values_mut() {
+            let _ = reader.get_mut().close();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Method {
+    #[default]
+    Get,
+    Post,
+}
+
+impl Method {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+        }
+    }
+
+    /// Returns whether sending the request twice has the same effect as sending it once, which
+    /// is what makes it safe to retry.
+    pub const fn is_idempotent(self) -> bool {
+        matches!(self, Method::Get)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Version {
+    #[default]
+    Http11,
+    /// Sends `HTTP/1.0` requests, which close their connection after each response.
+    Http10,
+}
+
+impl Version {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Version::Http11 => "HTTP/1.1",
+            Version::Http10 => "HTTP/1.0",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RequestOptions {
+    pub method: Method,
+    pub version: Version,
+    pub body: Option<Vec<u8>>,
+    pub content_type: Option<String>,
+    /// Headers to send on top of the standard ones, replacing those of the same name.
+    pub headers: Headers,
+    /// Sent as the `Host` header instead of the host of the URL, which is still the one
+    /// connected to, as when testing a virtual host on a given address.
+    pub host: Option<String>,
+}
+
+/// A `Content-Type` split into its media type and parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentType {
+    /// The `type/subtype`, lowercased.
+    pub media_type: String,
+    /// The parameters in the order they were given, with names lowercased and values unquoted.
+    pub parameters: Vec<(String, String)>,
+}
+
+impl ContentType {
+    /// Parses `type/subtype; name=value; name="quoted value"`, returning `None` if the media type
+    /// isn't of that form.
+    ///
+    /// Parameters without a name or a value are skipped.
+    pub fn parse(value: &str) -> Option<Self> {
+        // A `;` inside a quoted value doesn't end its parameter.
+        let mut fields = Vec::new();
+        let (mut start, mut quoted, mut escaped) = (0, false, false);
+        for (i, c) in value.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                ';' if !quoted => {
+                    fields.push(&value[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        fields.push(&value[start..]);
+
+        let mut fields = fields.into_iter();
+        let media_type = fields.next()?.trim().to_ascii_lowercase();
+        let (kind, subtype) = media_type.split_once('/')?;
+        if kind.is_empty() || subtype.is_empty() {
+            return None;
+        }
+
+        let parameters = fields
+            .filter_map(|field| {
+                let (name, value) = field.split_once('=')?;
+                let name = name.trim().to_ascii_lowercase();
+                let value = Self::unquote(value.trim());
+                (!name.is_empty() && !value.is_empty()).then_some((name, value))
+            })
+            .collect();
+
+        Some(Self {
+            media_type,
+            parameters,
+        })
+    }
+
+    fn unquote(value: &str) -> String {
+        let Some(value) = value
+            .strip_prefix('"')
+            .map(|v| v.strip_suffix('"').unwrap_or(v))
+        else {
+            return value.to_string();
+        };
+
+        let mut unquoted = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => unquoted.extend(chars.next()),
+                c => unquoted.push(c),
+            }
+        }
+        unquoted
+    }
+
+    /// Returns the value of the first parameter called `name`.
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response<'a> {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Box<dyn Read + 'a>,
+}
+
+impl<'a> Response<'a> {
+    pub fn ok(body: impl Read + 'a) -> Self {
+        Self {
+            status: 200,
+            headers: Headers::default(),
+            body: Box::new(body),
+        }
+    }
+
+    fn with_content_type(mut self, media_type: Option<&str>) -> Self {
+        if let Some(media_type) = media_type.filter(|t| !t.is_empty()) {
+            self.headers.insert("content-type", media_type);
+        }
+        self
+    }
+
+    /// Returns how long the server asked to wait before retrying, if this response is one that
+    /// asks to be retried later.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if !matches!(self.status, 429 | 503) {
+            return None;
+        }
+
+        let retry_after = self.headers.get("retry-after")?.trim();
+        match retry_after.parse() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => {
+                let date = parse_http_date(retry_after)?;
+                Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+            }
+        }
+    }
+
+    pub const fn is_redirect(status: u16) -> bool {
+        matches!(status, 301 | 302 | 303 | 307 | 308)
+    }
+
+    /// Returns the parsed `Content-Type` of the body, if it has a valid one.
+    pub fn content_type(&self) -> Option<ContentType> {
+        ContentType::parse(self.headers.get("content-type")?)
+    }
+
+    /// Returns whether the body is HTML, assuming so when its type is unknown.
+    pub fn is_html(&self) -> bool {
+        self.content_type()
+            .is_none_or(|ContentType { media_type, .. }| {
+                matches!(media_type.as_str(), "text/html" | "application/xhtml+xml")
+            })
+    }
+
+    /// Returns whether the body is meant to be read as text, assuming so when its type is
+    /// unknown.
+    pub fn is_text(&self) -> bool {
+        self.content_type()
+            .is_none_or(|ContentType { media_type, .. }| {
+                media_type.starts_with("text/")
+                    || media_type.ends_with("+xml")
+                    || media_type.ends_with("+json")
+                    || matches!(
+                        media_type.as_str(),
+                        "application/json" | "application/xml" | "application/javascript"
+                    )
+            })
+    }
+
+    /// Reads the remainder of the body into a string, replacing any invalid UTF-8.
+    pub fn text(mut self) -> io::Result<String> {
+        let mut body = Vec::new();
+        self.body.read_to_end(&mut body)?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UrlParseError {
+    TooLong {
+        length: usize,
+        max_length: usize,
+    },
+    /// A `data:` URL without the comma separating the media type from the content.
+    MalformedDataUrl,
+    /// A URL without the `scheme://` it is fetched with.
+    MissingScheme,
+    UnsupportedScheme(String),
+    InvalidPort(String),
+    /// A URL of a known scheme that names an action, like sending mail, rather than a document.
+    NotFetchable(String),
+}
+
+impl fmt::Display for UrlParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlParseError::TooLong { length, max_length } => {
+                write!(
+                    f,
+                    "URL is too long ({length} bytes, maximum is {max_length})"
+                )
+            }
+            UrlParseError::MalformedDataUrl => write!(f, "data: URL is missing a comma"),
+            UrlParseError::MissingScheme => write!(f, "URL is missing its scheme"),
+            UrlParseError::UnsupportedScheme(scheme) => write!(f, "Unsupported scheme: {scheme}"),
+            UrlParseError::InvalidPort(port) => write!(f, "Invalid port: {port}"),
+            UrlParseError::NotFetchable(scheme) => write!(f, "{scheme}: links are not fetchable"),
+        }
+    }
+}
+
+impl Error for UrlParseError {}
+
+/// The host of a URL could not be resolved to any address.
+#[derive(Debug)]
+pub struct ResolveError {
+    pub host: String,
+    source: io::Error,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to resolve {}: {}", self.host, self.source)
+    }
+}
+
+impl Error for ResolveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Why loading a URL failed.
+#[derive(Debug)]
+pub enum RequestError {
+    /// Resolving, connecting, the TLS session or reading the response failed, with a
+    /// [`ResolveError`] or [`rustls::Error`] inside for the failures of those stages.
+    Io(io::Error),
+    /// The redirect chain came back to a URL it had already visited.
+    RedirectCycle(Vec<Url>),
+    /// The redirect chain kept sending to the same resource with a different query.
+    RedirectQueryLoop(Vec<Url>),
+    TooManyRedirects(Vec<Url>),
+    /// The redirect chain went from `https` to plain `http`.
+    InsecureRedirect(Vec<Url>),
+    /// The redirect chain left the origin of the first URL while only same-origin redirects were
+    /// allowed.
+    CrossOriginRedirect(Vec<Url>),
+    /// The last response of the redirect chain had no location to follow, or an invalid one.
+    InvalidLocation(Vec<Url>, Option<String>),
+    /// The server answered with a client or server error, after its body was shown.
+    Status(u16),
+    /// The whole load, redirects and retries included, ran out of its time budget.
+    DeadlineExceeded,
+}
+
+impl RequestError {
+    fn fmt_chain(f: &mut fmt::Formatter<'_>, chain: &[Url]) -> fmt::Result {
+        for (i, url) in chain.iter().enumerate() {
+            let separator = if i == 0 { ":" } else { " ->" };
+            write!(f, "{separator} {url}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Io(e) => write!(f, "{e}"),
+            RequestError::RedirectCycle(chain) => {
+                write!(f, "Redirection chain has a cycle")?;
+                Self::fmt_chain(f, chain)
+            }
+            RequestError::RedirectQueryLoop(chain) => {
+                write!(
+                    f,
+                    "Redirection chain only changes the query of the same resource"
+                )?;
+                Self::fmt_chain(f, chain)
+            }
+            RequestError::TooManyRedirects(chain) => {
+                write!(f, "Too many redirects")?;
+                Self::fmt_chain(f, chain)
+            }
+            RequestError::InsecureRedirect(chain) => {
+                write!(f, "Refusing to follow redirect from https to http")?;
+                Self::fmt_chain(f, chain)
+            }
+            RequestError::CrossOriginRedirect(chain) => {
+                write!(f, "Refusing to follow redirect to another origin")?;
+                Self::fmt_chain(f, chain)
+            }
+            RequestError::InvalidLocation(chain, None) => {
+                write!(f, "Redirect has no location")?;
+                Self::fmt_chain(f, chain)
+            }
+            RequestError::InvalidLocation(chain, Some(location)) => {
+                write!(f, "Redirect location {location:?} is not a valid URL")?;
+                Self::fmt_chain(f, chain)
+            }
+            RequestError::Status(status) => write!(f, "Server responded with status {status}"),
+            RequestError::DeadlineExceeded => write!(f, "Overall deadline exceeded"),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RequestError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Url {
+    Http {
+        view_source: bool,
+        addr: (String, u16),
+        path: PathBuf,
+        fragment: Option<String>,
+    },
+    Https {
+        view_source: bool,
+        addr: (String, u16),
+        path: PathBuf,
+        fragment: Option<String>,
+    },
+    /// A page of the Gemini protocol, fetched with a one-line request over TLS.
+    Gemini {
+        view_source: bool,
+        addr: (String, u16),
+        path: PathBuf,
+        fragment: Option<String>,
+    },
+    File {
+        view_source: bool,
+        path: PathBuf,
+        fragment: Option<String>,
+    },
+    Data {
+        view_source: bool,
+        media_type: String,
+        content: String,
+        fragment: Option<String>,
+    },
+    About {
+        view_source: bool,
+        target: String,
+        fragment: Option<String>,
+    },
+}
+
+impl Url {
+    pub const DEFAULT_MAX_LENGTH: usize = 8 * 1024;
+
+    /// Well-known ports of the network schemes, used when a URL does not specify one.
+    const DEFAULT_PORTS: &'static [(&'static str, u16)] =
+        &[("http", 80), ("https", 443), ("gemini", 1965)];
+
+    /// Schemes of links that trigger an action in some other program instead of naming a
+    /// document, and so have nothing to fetch.
+    const NOT_FETCHABLE_SCHEMES: &'static [&'static str] = &["mailto"];
+
+    pub fn default_port(scheme: &str) -> Option<u16> {
+        Self::DEFAULT_PORTS
+            .iter()
+            .find(|(s, _)| *s == scheme)
+            .map(|&(_, port)| port)
+    }
+
+    pub fn new(url: &str) -> Result<Self, UrlParseError> {
+        Self::with_max_length(url, Self::DEFAULT_MAX_LENGTH)
+    }
+
+    pub fn with_max_length(url: &str, max_length: usize) -> Result<Self, UrlParseError> {
+        if url.len() > max_length {
+            return Err(UrlParseError::TooLong {
+                length: url.len(),
+                max_length,
+            });
+        }
+
+        let view_source = url.starts_with("view-source:");
+        let url = url.strip_prefix("view-source:").unwrap_or(url);
+        let (url, fragment) = Self::split_fragment(url);
+
+        if let Some(url) = url.strip_prefix("data:") {
+            let (media_type, content) =
+                url.split_once(',').ok_or(UrlParseError::MalformedDataUrl)?;
+            return Ok(Self::Data {
+                view_source,
+                media_type: media_type.to_string(),
+                content: content.to_string(),
+                fragment,
+            });
+        }
+
+        if let Some(target) = url.strip_prefix("about:") {
+            return Ok(Self::About {
+                view_source,
+                target: target.to_string(),
+                fragment,
+            });
+        }
+
+        if let Some(scheme) = url
+            .split_once(':')
+            .map(|(scheme, _)| scheme.to_ascii_lowercase())
+            .filter(|scheme| Self::NOT_FETCHABLE_SCHEMES.contains(&scheme.as_str()))
+        {
+            return Err(UrlParseError::NotFetchable(scheme));
+        }
+
+        let (scheme, url) = url.split_once("://").ok_or(UrlParseError::MissingScheme)?;
+        if scheme == "file" {
+            return Ok(Self::File {
+                view_source,
+                path: PathBuf::from(url),
+                fragment,
+            });
+        }
+
+        // The authority ends where the path or the query starts, and either may be missing.
+        let (mut host, path) = url.split_at(url.find(['/', '?']).unwrap_or(url.len()));
+        let path = PathBuf::from(format!("/{}", path.trim_start_matches('/')));
+        let mut port = Self::default_port(scheme)
+            .ok_or_else(|| UrlParseError::UnsupportedScheme(scheme.to_string()))?;
+
+        if host.contains(':') {
+            let addr = host.split_once(':').unwrap();
+            host = addr.0;
+            if !addr.1.is_empty() {
+                port = addr
+                    .1
+                    .parse()
+                    .map_err(|_| UrlParseError::InvalidPort(addr.1.to_string()))?;
+            }
+        }
+
+        let host = &Self::ascii_host(host);
+        match scheme {
+            "http" => Ok(Self::Http {
+                view_source,
+                addr: (host.to_string(), port),
+                path,
+                fragment,
+            }),
+            "https" => Ok(Self::Https {
+                view_source,
+                addr: (host.to_string(), port),
+                path,
+                fragment,
+            }),
+            "gemini" => Ok(Self::Gemini {
+                view_source,
+                addr: (host.to_string(), port),
+                path,
+                fragment,
+            }),
+            _ => Err(UrlParseError::UnsupportedScheme(scheme.to_string())),
+        }
+    }
+
+    /// Splits off the `#fragment`, which only matters to the client and is never fetched.
+    fn split_fragment(url: &str) -> (&str, Option<String>) {
+        match url.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment.to_string())),
+            None => (url, None),
+        }
+    }
+
+    pub const fn scheme(&self) -> &'static str {
+        match self {
+            Url::Http { .. } => "http",
+            Url::Https { .. } => "https",
+            Url::Gemini { .. } => "gemini",
+            Url::File { .. } => "file",
+            Url::Data { .. } => "data",
+            Url::About { .. } => "about",
+        }
+    }
+
+    pub const fn view_source(&self) -> bool {
+        match self {
+            Url::Http { view_source, .. }
+            | Url::Https { view_source, .. }
+            | Url::Gemini { view_source, .. }
+            | Url::File { view_source, .. }
+            | Url::Data { view_source, .. }
+            | Url::About { view_source, .. } => *view_source,
+        }
+    }
+
+    pub fn fragment(&self) -> Option<&str> {
+        match self {
+            Url::Http { fragment, .. }
+            | Url::Https { fragment, .. }
+            | Url::Gemini { fragment, .. }
+            | Url::File { fragment, .. }
+            | Url::Data { fragment, .. }
+            | Url::About { fragment, .. } => fragment.as_deref(),
+        }
+    }
+
+    fn fragment_mut(&mut self) -> &mut Option<String> {
+        match self {
+            Url::Http { fragment, .. }
+            | Url::Https { fragment, .. }
+            | Url::Gemini { fragment, .. }
+            | Url::File { fragment, .. }
+            | Url::Data { fragment, .. }
+            | Url::About { fragment, .. } => fragment,
+        }
+    }
+
+    /// Converts `host` to the ASCII form used on the wire, decoding any escapes and encoding each
+    /// label with non-ASCII characters as punycode, e.g. `münchen.de` becomes
+    /// `xn--mnchen-3ya.de`.
+    fn ascii_host(host: &str) -> String {
+        let host = Self::percent_decode(host);
+        if host.is_ascii() {
+            return host;
+        }
+
+        host.split('.')
+            .map(|label| {
+                if label.is_ascii() {
+                    label.to_string()
+                } else {
+                    format!("xn--{}", Self::punycode(&label.to_lowercase()))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// Encodes `label` with the punycode algorithm of RFC 3492.
+    fn punycode(label: &str) -> String {
+        const BASE: u64 = 36;
+        const T_MIN: u64 = 1;
+        const T_MAX: u64 = 26;
+
+        fn adapt(delta: u64, points: u64, first: bool) -> u64 {
+            let mut delta = if first { delta / 700 } else { delta / 2 };
+            delta += delta / points;
+            let mut k = 0;
+            while delta > (BASE - T_MIN) * T_MAX / 2 {
+                delta /= BASE - T_MIN;
+                k += BASE;
+            }
+            k + (BASE - T_MIN + 1) * delta / (delta + 38)
+        }
+
+        fn digit(d: u64) -> char {
+            let d = d as u8;
+            char::from(if d < 26 { b'a' + d } else { b'0' + d - 26 })
+        }
+
+        let code_points = label.chars().map(u64::from).collect::<Vec<_>>();
+        let mut output = label.chars().filter(char::is_ascii).collect::<String>();
+        let basic = output.len() as u64;
+        if basic > 0 {
+            output.push('-');
+        }
+
+        let (mut n, mut delta, mut bias, mut handled) = (128, 0, 72, basic);
+        while (handled as usize) < code_points.len() {
+            let m = code_points
+                .iter()
+                .copied()
+                .filter(|&c| c >= n)
+                .min()
+                .unwrap();
+            delta += (m - n) * (handled + 1);
+            n = m;
+
+            for &c in &code_points {
+                if c < n {
+                    delta += 1;
+                }
+                if c != n {
+                    continue;
+                }
+
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = k.saturating_sub(bias).clamp(T_MIN, T_MAX);
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+
+                output.push(digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+
+            delta += 1;
+            n += 1;
+        }
+
+        output
+    }
+
+    /// Formats the authority sent in the `Host` header, which carries the port only when it
+    /// differs from the scheme's default, e.g. `example.com:8443` for `https://example.com:8443/`.
+    ///
+    /// Returns `None` for URLs without a host.
+    fn display_host(&self) -> Option<String> {
+        let (Url::Http { addr: (h, p), .. }
+        | Url::Https { addr: (h, p), .. }
+        | Url::Gemini { addr: (h, p), .. }) = self
+        else {
+            return None;
+        };
+
+        if Self::default_port(self.scheme()) == Some(*p) {
+            Some(h.to_string())
+        } else {
+            Some(format!("{h}:{p}"))
+        }
+    }
+
+    /// Percent-encodes the bytes of `path` that may not appear in a request target, leaving
+    /// existing `%XX` escapes untouched.
+    fn encode_path(path: &str) -> String {
+        const ALLOWED: &[u8] = b"-._~!$&'()*+,;=:@/?";
+
+        let bytes = path.as_bytes();
+        let mut encoded = String::with_capacity(path.len());
+        for (i, &b) in bytes.iter().enumerate() {
+            let escape = b == b'%'
+                && bytes
+                    .get(i + 1..i + 3)
+                    .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+
+            if escape || b.is_ascii_alphanumeric() || ALLOWED.contains(&b) {
+                encoded.push(char::from(b));
+            } else {
+                write!(&mut encoded, "%{b:02X}").unwrap();
+            }
+        }
+
+        encoded
+    }
+
+    /// Returns the path with its `%XX` escapes decoded, for showing to a person, or `None` for
+    /// URLs that aren't fetched from a host.
+    pub fn display_path(&self) -> Option<String> {
+        let (Url::Http { path, .. } | Url::Https { path, .. } | Url::Gemini { path, .. }) = self
+        else {
+            return None;
+        };
+
+        Some(Self::percent_decode(&path.to_string_lossy()))
+    }
+
+    /// Decodes the `%XX` escapes in `text`, replacing any invalid UTF-8 they produce.
+    fn percent_decode(text: &str) -> String {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .filter(|_| bytes[i] == b'%')
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+            match byte {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                None => {
+                    decoded.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+
+    pub fn request<'a>(
+        &self,
+        ctx: &'a mut RequestContext,
+        opts: &RequestOptions,
+    ) -> Result<Response<'a>, RequestError> {
+        if let Self::File { path, .. } = self {
+            let file = fs::File::open(path)?;
+            return Ok(Response::ok(file).with_content_type(Url::guess_media_type(path)));
+        }
+
+        if let Self::Data {
+            content,
+            media_type,
+            ..
+        } = self
+        {
+            // A data URL without a media type is US-ASCII text (RFC 2397 2), even when it still
+            // gives parameters such as its charset.
+            let media_type = match media_type.as_str() {
+                "" => Cow::Borrowed("text/plain;charset=US-ASCII"),
+                parameters if parameters.starts_with(';') => {
+                    Cow::Owned(format!("text/plain{parameters}"))
+                }
+                media_type => Cow::Borrowed(media_type),
+            };
+            let body = io::Cursor::new(content.clone().into_bytes());
+            return Ok(Response::ok(body).with_content_type(Some(&media_type)));
+        }
+
+        if let Self::About { target, .. } = self {
+            let content = match target.as_str() {
+                "blank" => String::new(),
+                _ => {
+                    format!("<title>about:{target}</title><p>There is no about:{target} page.</p>")
+                }
+            };
+            return Ok(Response::ok(io::Cursor::new(content.into_bytes())));
+        }
+
+        if let Self::Gemini { .. } = self {
+            return self.request_gemini(ctx);
+        }
+
+        let (Self::Http { addr, path, .. } | Self::Https { addr, path, .. }) = self else {
+            return Err(RequestContext::not_networked(self).into());
+        };
+
+        let host = addr.0.to_ascii_lowercase();
+        let path = path.to_string_lossy();
+        let cookie_path = path.split_once('?').map_or(&*path, |(path, _)| path);
+
+        let mut extra = self.context_headers(ctx);
+
+        // A cached response only stands in for requests sending the headers it varies by alike.
+        let sent = self.request_headers(opts, &extra);
+        let cacheable = opts.method == Method::Get;
+        if let Some(entry) = ctx
+            .cache
+            .get(self)
+            .filter(|entry| cacheable && entry.is_fresh() && entry.matches(&sent))
+        {
+            return Ok(entry.to_response());
+        }
+
+        let cached = if cacheable {
+            ctx.cache.remove(self).filter(|entry| entry.matches(&sent))
+        } else {
+            None
+        };
+
+        if let Some(entry) = &cached {
+            if let Some(etag) = &entry.etag {
+                extra.append("If-None-Match", etag.as_str());
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                extra.append("If-Modified-Since", last_modified.as_str());
+            }
+        }
+
+        let request = self.build_head(opts, &extra, ctx.forward_proxy(self).is_some())?;
+        let body = opts.body.as_deref().unwrap_or_default();
+
+        // Servers close idle keep-alive connections, so a pooled one gets a single retry when it
+        // turns out to have been closed before any response, as long as resending is harmless.
+        let pooled = ctx.is_connected(self);
+        let (status, headers) = match self.send(ctx, &request, body) {
+            Err(e)
+                if pooled
+                    && opts.method.is_idempotent()
+                    && Url::is_connection_lost(&e)
+                    && ctx.take_retry() =>
+            {
+                ctx.disconnect(self);
+                self.send(ctx, &request, body)?
+            }
+            result => result?,
+        };
+
+        ctx.cookies
+            .store(&host, cookie_path, headers.get_all("set-cookie"));
+
+        // A connection either side asked to close is taken out of the pool for this response.
+        let close = opts.version == Version::Http10
+            || headers
+                .get_all("connection")
+                .flat_map(|v| v.split(','))
+                .any(|option| option.trim().eq_ignore_ascii_case("close"));
+
+        if status == 304 {
+            // A 304 Not Modified response never carries a body, the cached one is still valid.
+            if close {
+                ctx.disconnect(self);
+            }
+
+            let Some(mut entry) = cached else {
+                return Ok(Response {
+                    status,
+                    headers,
+                    body: Box::new(io::empty()),
+                });
+            };
+
+            entry.headers.extend(headers);
+            let entry = CacheEntry::new(entry.headers, entry.body, &sent);
+            let response = entry.to_response();
+            ctx.cache.insert(self.clone(), entry);
+            return Ok(response);
+        }
+
+        if !cacheable || !CacheEntry::is_cacheable(status, &headers) {
+            let (limits, max_body_size, trace) = (ctx.decode_limits, ctx.max_body_size, ctx.trace);
+            let body = Url::read_body(
+                ctx.body_reader(self, close)?,
+                status,
+                &headers,
+                limits,
+                close,
+            )?;
+            let body = RequestContext::limit_body(max_body_size, body);
+            let body = RequestContext::trace_body(trace, self, body);
+            return Ok(Response {
+                status,
+                headers,
+                body,
+            });
+        }
+
+        // Cacheable responses are buffered so they can be stored before being handed back.
+        let mut body = Vec::new();
+        let (limits, max_body_size, trace) = (ctx.decode_limits, ctx.max_body_size, ctx.trace);
+        let reader = Url::read_body(
+            ctx.body_reader(self, close)?,
+            status,
+            &headers,
+            limits,
+            close,
+        )?;
+        let reader = RequestContext::limit_body(max_body_size, reader);
+        let read = RequestContext::trace_body(trace, self, reader).read_to_end(&mut body);
+        match read {
+            Ok(_) => {}
+            // A body cut short is never cached, but what arrived of it is still handed back,
+            // followed by the failure, for callers that make do with a partial body.
+            Err(e) if Url::is_connection_lost(&e) => {
+                ctx.disconnect(self);
+                let body = Box::new(Truncated {
+                    read: io::Cursor::new(body),
+                    error: Some(e),
+                });
+                return Ok(Response {
+                    status,
+                    headers,
+                    body,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let entry = CacheEntry::new(headers, body, &sent);
+        let response = entry.to_response();
+        ctx.cache.insert(self.clone(), entry);
+        Ok(response)
+    }
+
+    /// Builds the request exactly as it would be sent for this URL, body included, without
+    /// anything a [`RequestContext`] would add from earlier responses.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] for `file`, `data` and `about` URLs, which send
+    /// no request.
+    pub fn build_request(&self, opts: &RequestOptions) -> io::Result<String> {
+        if let Self::Gemini { .. } = self {
+            return Ok(self.gemini_request());
+        }
+
+        let body = opts.body.as_deref().unwrap_or_default();
+        Ok(self.build_head(opts, &Headers::default(), false)? + &String::from_utf8_lossy(body))
+    }
+
+    /// Builds the request as it would be sent through `ctx` for this URL, body included, with
+    /// the target, user agent, cookies and proxy credentials the context adds, though without
+    /// any cache validators.
+    pub fn build_request_with(
+        &self,
+        opts: &RequestOptions,
+        ctx: &mut RequestContext,
+    ) -> io::Result<String> {
+        if let Self::Gemini { .. } = self {
+            return Ok(self.gemini_request());
+        }
+
+        let extra = self.context_headers(ctx);
+        let absolute_form = ctx.forward_proxy(self).is_some();
+        let body = opts.body.as_deref().unwrap_or_default();
+        Ok(self.build_head(opts, &extra, absolute_form)? + &String::from_utf8_lossy(body))
+    }
+
+    /// Collects the headers `ctx` adds to requests for this URL: its user agent, the cookies
+    /// stored for it and the credentials of the proxy it goes through.
+    fn context_headers(&self, ctx: &mut RequestContext) -> Headers {
+        let mut extra = Headers::default();
+        let (Self::Http { addr, path, .. } | Self::Https { addr, path, .. }) = self else {
+            return extra;
+        };
+
+        if let Some(user_agent) = &ctx.user_agent {
+            extra.append("User-Agent", user_agent.as_str());
+        }
+
+        let host = addr.0.to_ascii_lowercase();
+        let path = path.to_string_lossy();
+        let cookie_path = path.split_once('?').map_or(&*path, |(path, _)| path);
+        let secure = matches!(self, Self::Https { .. });
+        if let Some(cookie) = ctx.cookies.header(&host, cookie_path, secure) {
+            extra.append("Cookie", cookie);
+        }
+
+        if let Some(authorization) = ctx.forward_proxy(self).and_then(Proxy::authorization) {
+            extra.append("Proxy-Authorization", authorization);
+        }
+
+        extra
+    }
+
+    /// Builds the head of an HTTP request up to the blank line before the body, with `extra`
+    /// headers like cookies and cache validators after the standard ones.
+    ///
+    /// Requests sent to a proxy name the whole URL in their `absolute_form` target.
+    fn build_head(
+        &self,
+        opts: &RequestOptions,
+        extra: &Headers,
+        absolute_form: bool,
+    ) -> io::Result<String> {
+        let (Self::Http { path, .. } | Self::Https { path, .. }) = self else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only http, https and gemini URLs send requests",
+            ));
+        };
+
+        let mut request = String::new();
+        let method = opts.method.as_str();
+        let mut target = Url::encode_path(&Url::normalize_path(&path.to_string_lossy()));
+        if let Some(host) = self.display_host().filter(|_| absolute_form) {
+            target = format!("{}://{host}{target}", self.scheme());
+        }
+        let version = opts.version.as_str();
+        write!(&mut request, "{method} {target} {version}\r\n").unwrap();
+
+        let headers = self.request_headers(opts, extra);
+        for (name, value) in headers.iter() {
+            let name = Self::header_case(name);
+            write!(&mut request, "{name}: {value}\r\n").unwrap();
+        }
+
+        let body = opts.body.as_deref().unwrap_or_default();
+        if opts.method == Method::Post || !body.is_empty() {
+            if let Some(content_type) = opts
+                .content_type
+                .as_ref()
+                .filter(|_| !headers.contains_key("content-type"))
+            {
+                write!(&mut request, "Content-Type: {content_type}\r\n").unwrap();
+            }
+            write!(&mut request, "Content-Length: {}\r\n", body.len()).unwrap();
+        }
+        write!(&mut request, "\r\n").unwrap();
+
+        Ok(request)
+    }
+
+    /// Lists the headers of a request after its request line, leaving out the ones describing its
+    /// body: the standard ones, then `extra` and the ones from `opts`, each replacing any of the
+    /// same name before it.
+    fn request_headers(&self, opts: &RequestOptions, extra: &Headers) -> Headers {
+        let agent = env!("CARGO_PKG_VERSION");
+        let connection = match opts.version {
+            Version::Http11 => "keep-alive",
+            Version::Http10 => "close",
+        };
+
+        let mut headers = Headers::default();
+        if let Some(host) = opts.host.clone().or_else(|| self.display_host()) {
+            headers.append("host", host);
+        }
+        headers.append("connection", connection);
+        headers.append("accept", "text/html, text/*;q=0.9, */*;q=0.1");
+        headers.append("accept-encoding", "gzip, deflate");
+        headers.append("user-agent", format!("vanadium/{agent}"));
+        headers.extend(extra.clone());
+        headers.extend(opts.headers.clone());
+        headers
+    }
+
+    /// Capitalizes each dash-separated word of a lowercased header name, as in `If-None-Match`.
+    fn header_case(name: &str) -> String {
+        name.split('-')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or_else(String::new, |first| {
+                    first.to_ascii_uppercase().to_string() + chars.as_str()
+                })
+            })
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Builds the one-line request for a Gemini URL, its path prepared like an HTTP request
+    /// target and without `view-source:` or a fragment, which are never sent.
+    fn gemini_request(&self) -> String {
+        let Self::Gemini { path, .. } = self else {
+            unreachable!("only Gemini URLs are requested as such");
+        };
+
+        let host = self.display_host().unwrap_or_default();
+        let target = Url::encode_path(&Url::normalize_path(&path.to_string_lossy()));
+        format!("gemini://{host}{target}\r\n")
+    }
+
+    /// Fetches a Gemini page, mapping its two-digit status onto the closest HTTP one so that
+    /// redirects and failures are handled like those of the web.
+    fn request_gemini<'a>(
+        &self,
+        ctx: &'a mut RequestContext,
+    ) -> Result<Response<'a>, RequestError> {
+        let request = self.gemini_request();
+        let verbose = ctx.verbose;
+        if verbose {
+            eprintln!("> {}", request.trim_end());
+        }
+
+        // The server closes the connection after responding, so it is never kept in the pool.
+        let (trace, addr) = (ctx.trace, RequestContext::addr(self)?);
+        let started = Instant::now();
+        let written = ctx.stream(self)?.write_all(request.as_bytes());
+        ctx.record(
+            Phase::RequestWrite,
+            addr,
+            started.elapsed(),
+            written.is_ok(),
+        );
+        written?;
+
+        let mut line = String::new();
+        let started = Instant::now();
+        let read = ctx.timed_reader(self)?.read_line(&mut line);
+        ctx.record(Phase::ResponseHead, addr, started.elapsed(), read.is_ok());
+        read?;
+        let reader = ctx.body_reader(self, true)?;
+        if verbose {
+            eprintln!("< {}", line.trim_end());
+        }
+
+        let (code, meta) = line
+            .trim_end()
+            .split_once(' ')
+            .unwrap_or((line.trim_end(), ""));
+        let code = code
+            .parse::<u8>()
+            .ok()
+            .filter(|code| (10..70).contains(code))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Malformed Gemini response line: {:?}", line.trim_end()),
+                )
+            })?;
+
+        let mut headers = Headers::default();
+        let status = match code {
+            20..=29 => {
+                let media_type = if meta.is_empty() { "text/gemini" } else { meta };
+                headers.insert("content-type", media_type);
+                let body = Box::new(BodyReader::until_close(reader));
+                return Ok(Response {
+                    status: 200,
+                    headers,
+                    body: RequestContext::trace_body(trace, self, body),
+                });
+            }
+            30 | 32..=39 => 302,
+            31 => 301,
+            44 => 429,
+            40..=49 => 503,
+            51 => 404,
+            52 => 410,
+            50..=59 => 400,
+            60..=69 => 401,
+            _ => 400,
+        };
+
+        if Response::is_redirect(status) {
+            headers.insert("location", meta);
+        } else {
+            headers.insert("content-type", "text/plain");
+        }
+
+        // Failures only come with the human readable `meta`, which stands in for the body.
+        let body = io::Cursor::new(meta.to_string().into_bytes());
+        Ok(Response {
+            status,
+            headers,
+            body: Box::new(body),
+        })
+    }
+
+    /// Guesses the media type of a local file from its extension.
+    fn guess_media_type(path: &Path) -> Option<&'static str> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        let media_type = match extension.as_str() {
+            "html" | "htm" => "text/html",
+            "md" | "markdown" => "text/markdown",
+            "txt" => "text/plain",
+            "css" => "text/css",
+            "csv" => "text/csv",
+            "js" => "text/javascript",
+            "json" => "application/json",
+            "xml" => "application/xml",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "ico" => "image/x-icon",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "gz" => "application/gzip",
+            "wasm" => "application/wasm",
+            "mp3" => "audio/mpeg",
+            "mp4" => "video/mp4",
+            _ => return None,
+        };
+
+        Some(media_type)
+    }
+
+    fn send(
+        &self,
+        ctx: &mut RequestContext,
+        head: &str,
+        body: &[u8],
+    ) -> io::Result<(u16, Headers)> {
+        let verbose = ctx.verbose;
+        if verbose {
+            for line in head.lines() {
+                eprintln!("> {line}");
+            }
+        }
+
+        let max_size = ctx
+            .max_header_size
+            .unwrap_or(RequestContext::DEFAULT_MAX_HEADER_SIZE);
+        let mut request = Vec::with_capacity(head.len() + body.len());
+        request.extend_from_slice(head.as_bytes());
+        request.extend_from_slice(body);
+
+        let (trace, addr) = (ctx.trace, RequestContext::addr(self)?);
+        if trace && ctx.is_connected(self) {
+            eprintln!("* Reusing connection to {}:{}", addr.0, addr.1);
+        }
+        let stream = ctx.stream(self)?;
+        let started = Instant::now();
+        let written = stream.write_all(&request);
+        ctx.record(
+            Phase::RequestWrite,
+            addr,
+            started.elapsed(),
+            written.is_ok(),
+        );
+        written?;
+
+        let mut reader = ctx.timed_reader(self)?;
+        let started = Instant::now();
+        let head = Url::read_head(&mut reader, verbose, max_size);
+        ctx.record(Phase::ResponseHead, addr, started.elapsed(), head.is_ok());
+        head
+    }
+
+    /// Returns whether `error` is how a connection fails once the other end is gone: it is reset,
+    /// breaks on writing, or ends before the response does.
+    fn is_connection_lost(error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+        )
+    }
+
+    /// Parses the status code out of a status line, which may omit its reason phrase.
+    fn parse_status_line(line: &str) -> io::Result<u16> {
+        let mut parts = line.trim_end_matches(['\r', '\n']).splitn(3, ' ');
+        let version = parts.next().unwrap_or_default();
+        let status = parts
+            .next()
+            .filter(|status| status.len() == 3)
+            .and_then(|status| status.parse().ok());
+        let _reason = parts.next().unwrap_or_default();
+
+        match status {
+            Some(status) if version.starts_with("HTTP/") => Ok(status),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed status line: {:?}", line.trim_end()),
+            )),
+        }
+    }
+
+    /// Reads the status line and headers of the final response, skipping over any interim
+    /// `1xx` responses sent ahead of it.
+    ///
+    /// The heads may take up to `max_size` bytes between them, past which the response is
+    /// rejected, so that a server can't keep the client reading interim responses forever.
+    fn read_head(
+        reader: &mut impl BufRead,
+        verbose: bool,
+        max_size: usize,
+    ) -> io::Result<(u16, Headers)> {
+        let mut remaining = max_size;
+        loop {
+            let mut read_line = |line: &mut String| {
+                // Reading one byte past the limit tells a head that reaches it from one that
+                // overflows it.
+                let n = reader.by_ref().take(remaining as u64 + 1).read_line(line)?;
+                remaining = remaining.checked_sub(n).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Response head exceeds {max_size} bytes"),
+                    )
+                })?;
+                Ok::<_, io::Error>(n)
+            };
+
+            let mut statusline = String::new();
+            if read_line(&mut statusline)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            if verbose {
+                eprintln!("< {}", statusline.trim_end());
+            }
+
+            let status = Url::parse_status_line(&statusline)?;
+
+            let mut response_headers = Headers::default();
+            loop {
+                let mut line = String::new();
+                read_line(&mut line)?;
+                if verbose {
+                    eprintln!("< {}", line.trim_end());
+                }
+
+                // Servers are expected to end lines with CRLF, but some only send LF.
+                let line = line.trim_end_matches(['\r', '\n']);
+                if line.is_empty() {
+                    break;
+                }
+
+                let (header, value) = line.split_once(':').ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Malformed header line: {line:?}"),
+                    )
+                })?;
+                response_headers.append(header, value.trim_matches([' ', '\t']));
+            }
+
+            if !(100..200).contains(&status) {
+                return Ok((status, response_headers));
+            }
+        }
+    }
+
+    /// Reads the body of a response off `reader`, which holds on to a connection that is closing
+    /// when `until_close` is set, so a body without a length simply runs until its end.
+    fn read_body<'a>(
+        reader: impl BufRead + 'a,
+        status: u16,
+        response_headers: &Headers,
+        limits: DecodeLimits,
+        until_close: bool,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        // Responses of these statuses end with their head, whatever it says (RFC 9112 6.3).
+        if status == 204 || status == 304 || (100..200).contains(&status) {
+            return Ok(Box::new(BodyReader::with_length(reader, 0)));
+        }
+
+        // Chunked framing wins over a content-length sent along with it (RFC 7230 3.3.3), which
+        // only a broken or malicious server would do.
+        let chunked = response_headers
+            .get_all("transfer-encoding")
+            .flat_map(|v| v.split(','))
+            .last()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        let body = if chunked {
+            BodyReader::chunked(reader)
+        } else if until_close && !response_headers.contains_key("content-length") {
+            BodyReader::until_close(reader)
+        } else {
+            // Repeated lengths are only tolerated when they all agree (RFC 9112 6.3).
+            let mut lengths = response_headers
+                .get_all("content-length")
+                .flat_map(|v| v.split(','))
+                .map(str::trim);
+            let content_length = lengths.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Missing content-length header in HTTP response",
+                )
+            })?;
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid content-length header: {content_length}"),
+                )
+            };
+            if lengths.any(|length| length != content_length) {
+                return Err(invalid());
+            }
+            let content_length = content_length.parse::<usize>().map_err(|_| invalid())?;
+            BodyReader::with_length(reader, content_length)
+        };
+
+        // Each repetition of the header lists codings applied after those of the previous one.
+        let encoding = response_headers
+            .get_all("content-encoding")
+            .collect::<Vec<_>>()
+            .join(",");
+        if encoding.is_empty() {
+            return Ok(Box::new(body));
+        }
+
+        let encoded = Rc::new(Cell::new(0));
+        let counted = CountingReader {
+            inner: body,
+            count: Rc::clone(&encoded),
+        };
+        Ok(Box::new(LimitedDecoder {
+            inner: Url::decoder(counted, Some(&encoding))?,
+            encoded,
+            decoded: 0,
+            limits,
+        }))
+    }
+
+    /// Wraps `reader` with a streaming decoder for each of the listed content codings.
+    ///
+    /// Codings are listed in the order they were applied, so they are undone in reverse.
+    fn decoder<'a>(
+        reader: impl Read + 'a,
+        content_encoding: Option<&str>,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        let mut reader: Box<dyn Read + 'a> = Box::new(reader);
+        for encoding in content_encoding.unwrap_or_default().split(',').rev() {
+            reader = match encoding.trim().to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" => Box::new(GzDecoder::new(reader)),
+                "deflate" => Box::new(ZlibDecoder::new(reader)),
+                "identity" | "" => reader,
+                // Brotli is never advertised in `Accept-Encoding`, there being no decoder for it.
+                "br" => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unsupported content-encoding: br, brotli decoding is not built in",
+                    ))
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unsupported content-encoding: {other}"),
+                    ))
+                }
+            };
+        }
+
+        Ok(reader)
+    }
+
+    /// Removes the `.` and `..` segments of `path` (RFC 3986 5.2.4), never climbing above the
+    /// root, and leaves its query as it is.
+    fn normalize_path(path: &str) -> String {
+        let (resource, query) = path.split_at(path.find('?').unwrap_or(path.len()));
+        let Some(resource) = resource.strip_prefix('/') else {
+            return path.to_string();
+        };
+
+        let mut segments = Vec::new();
+        for segment in resource.split('/') {
+            match segment {
+                "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                segment => segments.push(segment),
+            }
+        }
+
+        // A path ending in a dot-segment names a directory, so it keeps its trailing slash.
+        if matches!(resource.rsplit('/').next(), Some("." | "..")) {
+            segments.push("");
+        }
+
+        format!("/{}{query}", segments.join("/"))
+    }
+
+    /// Rewrites the escapes in `path` to one spelling, decoding those of unreserved characters
+    /// and uppercasing the rest, and drops an empty query.
+    fn canonical_path(path: &str) -> String {
+        let encoded = Self::encode_path(path);
+        let mut canonical = String::with_capacity(encoded.len());
+        let mut rest = encoded.as_str();
+        while let Some(i) = rest.find('%') {
+            canonical.push_str(&rest[..i]);
+            let byte = rest
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+            match byte {
+                Some(b) if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => {
+                    canonical.push(char::from(b));
+                    rest = &rest[i + 3..];
+                }
+                Some(b) => {
+                    write!(&mut canonical, "%{b:02X}").unwrap();
+                    rest = &rest[i + 3..];
+                }
+                None => {
+                    canonical.push('%');
+                    rest = &rest[i + 1..];
+                }
+            }
+        }
+
+        canonical.push_str(rest);
+        if canonical.ends_with('?') {
+            canonical.pop();
+        }
+
+        canonical
+    }
+
+    /// Returns the form of this URL used to tell whether two of them name the same resource.
+    ///
+    /// It ignores `view-source:` and the fragment, lowercases the host and settles on a single
+    /// spelling of the path and query, without dot-segments.
+    pub fn normalized(&self) -> Self {
+        let mut url = self.clone();
+        match &mut url {
+            Url::Http {
+                view_source,
+                addr,
+                path,
+                ..
+            }
+            | Url::Https {
+                view_source,
+                addr,
+                path,
+                ..
+            }
+            | Url::Gemini {
+                view_source,
+                addr,
+                path,
+                ..
+            } => {
+                *view_source = false;
+                addr.0.make_ascii_lowercase();
+                let canonical = Self::canonical_path(&path.to_string_lossy());
+                *path = PathBuf::from(Self::normalize_path(&canonical));
+            }
+            Url::File { view_source, .. }
+            | Url::Data { view_source, .. }
+            | Url::About { view_source, .. } => *view_source = false,
+        }
+
+        *url.fragment_mut() = None;
+        url
+    }
+
+    /// Returns whether both URLs are fetched over the same scheme from the same host and port.
+    pub fn same_origin(&self, other: &Url) -> bool {
+        match (self, other) {
+            (Url::Http { addr: a, .. }, Url::Http { addr: b, .. })
+            | (Url::Https { addr: a, .. }, Url::Https { addr: b, .. })
+            | (Url::Gemini { addr: a, .. }, Url::Gemini { addr: b, .. }) => {
+                a.0.eq_ignore_ascii_case(&b.0) && a.1 == b.1
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns this URL without its query, for telling apart redirects that only change it.
+    fn without_query(&self) -> Self {
+        let mut url = self.clone();
+        if let Url::Http { path, .. } | Url::Https { path, .. } | Url::Gemini { path, .. } =
+            &mut url
+        {
+            let resource = path
+                .to_string_lossy()
+                .split_once('?')
+                .map(|(resource, _)| PathBuf::from(resource));
+            if let Some(resource) = resource {
+                *path = resource;
+            }
+        }
+
+        url
+    }
+
+    /// Returns whether `reference` starts with a scheme, as in `https:` or `mailto:`.
+    fn has_scheme(reference: &str) -> bool {
+        reference.split_once(':').is_some_and(|(scheme, _)| {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        })
+    }
+
+    /// Resolves `reference`, as found in a link of the page at this URL, to the URL it points to
+    /// (RFC 3986 5.2).
+    ///
+    /// Only references with their own scheme can be resolved against `data:` and `about:` URLs,
+    /// which have no path to be relative to.
+    pub fn join(&self, reference: &str) -> Result<Self, UrlParseError> {
+        if Self::has_scheme(reference) {
+            return Url::new(reference);
+        }
+
+        // A scheme-relative reference keeps the scheme but names another host.
+        if reference.starts_with("//") {
+            return match self {
+                Url::Http { .. } | Url::Https { .. } | Url::Gemini { .. } => {
+                    Url::new(&format!("{}:{reference}", self.scheme()))
+                }
+                _ => Err(UrlParseError::MissingScheme),
+            };
+        }
+
+        let (reference, fragment) = Self::split_fragment(reference);
+        let mut joined = self.clone();
+        let (Url::Http { path, .. }
+        | Url::Https { path, .. }
+        | Url::Gemini { path, .. }
+        | Url::File { path, .. }) = &mut joined
+        else {
+            return Err(UrlParseError::MissingScheme);
+        };
+
+        let base = path.to_string_lossy();
+        let resource = base
+            .split_once('?')
+            .map_or(&*base, |(resource, _)| resource);
+        let target = if reference.is_empty() {
+            base.to_string()
+        } else if reference.starts_with('?') {
+            format!("{resource}{reference}")
+        } else if reference.starts_with('/') {
+            Self::normalize_path(reference)
+        } else {
+            let directory = resource.rfind('/').map_or("/", |i| &resource[..=i]);
+            Self::normalize_path(&format!("{directory}{reference}"))
+        };
+
+        *path = PathBuf::from(target);
+        *joined.fragment_mut() = fragment;
+        if let Url::Http { view_source, .. }
+        | Url::Https { view_source, .. }
+        | Url::Gemini { view_source, .. }
+        | Url::File { view_source, .. } = &mut joined
+        {
+            *view_source = false;
+        }
+
+        Ok(joined)
+    }
+
+    /// Resolves the `Location` of a redirect from this URL, which keeps this URL's fragment if it
+    /// has none of its own.
+    ///
+    /// Fails with [`UrlParseError::UnsupportedScheme`] for `file`, `data` and `about` URLs, which
+    /// are never redirected from.
+    pub fn follow(&self, location: String) -> Result<Self, UrlParseError> {
+        if !matches!(
+            self,
+            Url::Http { .. } | Url::Https { .. } | Url::Gemini { .. }
+        ) {
+            return Err(UrlParseError::UnsupportedScheme(self.scheme().to_string()));
+        }
+
+        let mut follower = self.join(&location)?;
+
+        // A location without a fragment inherits the one of the original URL (RFC 7231 7.1.2).
+        if follower.fragment().is_none() {
+            *follower.fragment_mut() = self.fragment().map(String::from);
+        }
+
+        Ok(follower)
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.view_source() {
+            write!(f, "view-source:")?;
+        }
+
+        match self {
+            Url::Http { path, .. } | Url::Https { path, .. } | Url::Gemini { path, .. } => {
+                let (scheme, host) = (self.scheme(), self.display_host().unwrap_or_default());
+                write!(f, "{scheme}://{host}{}", path.display())?;
+            }
+            Url::File { path, .. } => write!(f, "file://{}", path.display())?,
+            Url::Data {
+                media_type,
+                content,
+                ..
+            } => write!(f, "data:{media_type},{content}")?,
+            Url::About { target, .. } => write!(f, "about:{target}")?,
+        }
+
+        match self.fragment() {
+            Some(fragment) => write!(f, "#{fragment}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Resolves the name of an entity, as in `amp` for `&amp;` or `#x41` for `&#x41;`, to the text
+/// it stands for.
+fn entity(name: &str) -> Option<Cow<'static, str>> {
+    let named = match name {
+        "lt" => "<",
+        "gt" => ">",
+        "amp" => "&",
+        "quot" => "\"",
+        "apos" => "'",
+        "nbsp" => "\u{a0}",
+        _ => {
+            let number = name.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            return char::from_u32(code)
+                .filter(|&c| c != '\0')
+                .map(|c| Cow::Owned(c.to_string()));
+        }
+    };
+
+    Some(Cow::Borrowed(named))
+}
+
+/// Replaces the named and numeric entities in `text` with the characters they stand for,
+/// leaving the ones it doesn't know, or that lack their final `;`, as they were written.
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let name_len = rest[1..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '#'))
+            .unwrap_or(rest.len() - 1);
+        let name = &rest[1..1 + name_len];
+        match entity(name).filter(|_| rest[1 + name_len..].starts_with(';')) {
+            Some(entity) => {
+                decoded.push_str(&entity);
+                rest = &rest[name_len + 2..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Returns the value of the attribute `name` in the text of a tag, such as `a href="/"`.
+fn attribute<'t>(tag: &'t str, name: &str) -> Option<&'t str> {
+    let (_, mut rest) = tag.split_once(|c: char| c.is_ascii_whitespace())?;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if rest.is_empty() {
+            return None;
+        }
+
+        let end = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let (key, after) = rest.split_at(end);
+        let after = after.trim_start();
+
+        let (value, remaining) = match after.strip_prefix('=').map(str::trim_start) {
+            Some(quoted) if quoted.starts_with(['"', '\'']) => {
+                let quote = quoted.as_bytes()[0] as char;
+                let quoted = &quoted[1..];
+                let close = quoted.find(quote).unwrap_or(quoted.len());
+                (
+                    &quoted[..close],
+                    quoted.get(close + 1..).unwrap_or_default(),
+                )
+            }
+            Some(unquoted) => unquoted.split_at(
+                unquoted
+                    .find(|c: char| c.is_ascii_whitespace())
+                    .unwrap_or(unquoted.len()),
+            ),
+            None => ("", after),
+        };
+
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = remaining;
+    }
+}
+
+/// Finds the `<meta http-equiv="refresh">` of a page, returning its delay in seconds and the
+/// URL it refreshes to, if it names one.
+fn meta_refresh(html: &str) -> Option<(u64, String)> {
+    let tag = html
+        .split('<')
+        .filter_map(|tag| tag.split_once('>').map(|(tag, _)| tag))
+        .filter(|tag| {
+            tag.get(..5)
+                .is_some_and(|name| name.eq_ignore_ascii_case("meta "))
+        })
+        .find(|tag| {
+            attribute(tag, "http-equiv").is_some_and(|value| value.eq_ignore_ascii_case("refresh"))
+        })?;
+
+    // The content reads like `0; url=/next`, with the URL optionally quoted.
+    let content = attribute(tag, "content")?;
+    let (delay, target) = content.split_once([';', ',']).unwrap_or((content, ""));
+    let delay = delay.trim().split('.').next()?.parse().ok()?;
+    let target = target.trim_start();
+    let target = target
+        .get(..3)
+        .filter(|key| key.eq_ignore_ascii_case("url"))
+        .map_or(target, |_| &target[3..])
+        .trim_start()
+        .strip_prefix('=')?
+        .trim()
+        .trim_matches(['"', '\'']);
+
+    (!target.is_empty()).then(|| (delay, target.to_string()))
+}
+
+enum RenderState {
+    Text,
+    Tag,
+    Comment { dashes: usize },
+    Entity,
+}
+
+/// Strips tags and comments and decodes entities from HTML fed to it in arbitrary pieces.
+struct Renderer<W: Write> {
+    out: LineWrapper<W>,
+    state: RenderState,
+    buffer: String,
+    /// Prints the document title as a heading before anything else.
+    title_heading: bool,
+    in_title: bool,
+    title: String,
+    held: Vec<u8>,
+    released: bool,
+    /// The fragment of the page's URL, marked where an element names it with `id` or `name`.
+    anchor: Option<String>,
+    anchor_found: bool,
+    /// Collapses runs of whitespace into a single space, as browsers do outside `<pre>`.
+    collapse_whitespace: bool,
+    pre_depth: usize,
+    /// Whether any text was written since the start of the current line or of the title.
+    started: bool,
+    pending_space: bool,
+}
+
+impl<W: Write> Renderer<W> {
+    /// Renders to `out`, wrapping lines outside `<pre>` at `width` columns if given.
+    fn new(out: W, width: Option<usize>) -> Self {
+        Self {
+            out: LineWrapper::new(out, width),
+            state: RenderState::Text,
+            buffer: String::new(),
+            title_heading: false,
+            in_title: false,
+            title: String::new(),
+            held: Vec::new(),
+            released: false,
+            anchor: None,
+            anchor_found: false,
+            collapse_whitespace: false,
+            pre_depth: 0,
+            started: false,
+            pending_space: false,
+        }
+    }
+
+    fn feed(&mut self, text: &str) -> io::Result<()> {
+        text.chars().try_for_each(|c| self.push(c))
+    }
+
+    fn push(&mut self, c: char) -> io::Result<()> {
+        match self.state {
+            RenderState::Text if c == '<' => {
+                self.state = RenderState::Tag;
+                self.buffer.clear();
+            }
+            RenderState::Text if c == '&' => {
+                self.state = RenderState::Entity;
+                self.buffer.clear();
+                self.buffer.push(c);
+            }
+            RenderState::Text => self.emit_text(c.encode_utf8(&mut [0; 4]))?,
+            RenderState::Tag if c == '>' => {
+                self.state = RenderState::Text;
+                self.close_tag()?;
+            }
+            RenderState::Tag => {
+                self.buffer.push(c);
+                if self.buffer == "!--" {
+                    // Starting with the dashes of the opener also accepts the abruptly closed
+                    // `<!-->` and `<!--->`.
+                    self.state = RenderState::Comment { dashes: 2 };
+                }
+            }
+            RenderState::Comment { dashes } if c == '>' && dashes >= 2 => {
+                self.state = RenderState::Text;
+            }
+            RenderState::Comment { ref mut dashes } => {
+                *dashes = if c == '-' { *dashes + 1 } else { 0 };
+            }
+            RenderState::Entity if c == ';' => {
+                let buffer = mem::take(&mut self.buffer);
+                match entity(&buffer[1..]) {
+                    Some(entity) => self.emit_text(&entity)?,
+                    None => self.emit_text(&format!("{buffer};"))?,
+                }
+                self.state = RenderState::Text;
+            }
+            RenderState::Entity if c.is_ascii_alphanumeric() || c == '#' => self.buffer.push(c),
+            RenderState::Entity => {
+                let buffer = mem::take(&mut self.buffer);
+                self.emit_text(&buffer)?;
+                self.state = RenderState::Text;
+                self.push(c)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the buffered tag is a closing one along with its lowercase name.
+    fn tag_name(&self) -> (bool, String) {
+        let (closing, tag) = match self.buffer.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, self.buffer.as_str()),
+        };
+
+        let name = tag
+            .split(|c: char| c.is_ascii_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        (closing, name.to_ascii_lowercase())
+    }
+
+    fn close_tag(&mut self) -> io::Result<()> {
+        match self.tag_name() {
+            (false, name) if name == "title" => {
+                self.in_title = true;
+                self.restart_text();
+            }
+            (true, name) if name == "title" => {
+                self.in_title = false;
+                self.restart_text();
+                self.release()?;
+            }
+            (false, name) if name == "pre" => {
+                self.pre_depth += 1;
+                self.out.set_verbatim(true)?;
+            }
+            (true, name) if name == "pre" => {
+                self.pre_depth = self.pre_depth.saturating_sub(1);
+                self.out.set_verbatim(self.pre_depth > 0)?;
+            }
+            (false, name) if name == "body" => self.release()?,
+            (false, name) if name == "br" => self.line_break(true)?,
+            (false, name) if name == "li" => self.line_break(false)?,
+            (true, name)
+                if matches!(
+                    name.as_str(),
+                    "p" | "div" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "li"
+                ) =>
+            {
+                self.line_break(false)?
+            }
+            _ => {}
+        }
+
+        let Some(anchor) = self.anchor.as_deref().filter(|_| !self.anchor_found) else {
+            return Ok(());
+        };
+
+        let names = |key| attribute(&self.buffer, key).is_some_and(|value| value == anchor);
+        if !self.buffer.starts_with('/') && (names("id") || names("name")) {
+            self.anchor_found = true;
+            let marker = format!("[#{anchor}]");
+            self.emit(&marker)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends the current line, unless it is still empty and the break isn't a `forced` one.
+    fn line_break(&mut self, forced: bool) -> io::Result<()> {
+        if self.in_title || !(forced || self.started) {
+            return Ok(());
+        }
+
+        self.started = false;
+        self.pending_space = false;
+        self.emit("\n")
+    }
+
+    /// Starts collapsing whitespace afresh when the title heading is split from the text.
+    fn restart_text(&mut self) {
+        if self.title_heading && !self.released {
+            self.started = false;
+            self.pending_space = false;
+        }
+    }
+
+    /// Emits document text, holding back whitespace to collapse it unless inside `<pre>`.
+    fn emit_text(&mut self, text: &str) -> io::Result<()> {
+        if !self.collapse_whitespace || self.pre_depth > 0 {
+            self.started = true;
+            return self.emit(text);
+        }
+
+        for c in text.chars() {
+            // Only ASCII whitespace separates words in HTML, `&nbsp;` is kept as it is.
+            if c.is_ascii_whitespace() {
+                self.pending_space = self.started;
+                continue;
+            }
+
+            if mem::take(&mut self.pending_space) {
+                self.emit(" ")?;
+            }
+            self.started = true;
+            self.emit(c.encode_utf8(&mut [0; 4]))?;
+        }
+
+        Ok(())
+    }
+
+    fn emit(&mut self, text: &str) -> io::Result<()> {
+        if !self.title_heading || self.released {
+            self.out.write_all(text.as_bytes())
+        } else if self.in_title {
+            self.title.push_str(text);
+            Ok(())
+        } else {
+            self.held.extend_from_slice(text.as_bytes());
+            Ok(())
+        }
+    }
+
+    /// Writes the title heading followed by everything held back while looking for it.
+    fn release(&mut self) -> io::Result<()> {
+        if !self.title_heading || self.released {
+            return Ok(());
+        }
+
+        self.released = true;
+        let title = self.title.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !title.is_empty() {
+            let underline = "=".repeat(title.chars().count());
+            writeln!(self.out, "{title}\n{underline}")?;
+        }
+
+        self.out.write_all(&mem::take(&mut self.held))
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        // An entity cut off by the end of the document, like a trailing `&` or `&amp`, is printed
+        // once as it was written.
+        if let RenderState::Entity = self.state {
+            let buffer = mem::take(&mut self.buffer);
+            self.emit_text(&buffer)?;
+        }
+
+        // Collapsing drops the source's final newline along with the rest of its whitespace.
+        if self.collapse_whitespace && self.started {
+            self.emit("\n")?;
+        }
+
+        self.release()?;
+        self.out.flush()
+    }
+}
+
+/// Returns the length of an incomplete UTF-8 sequence at the end of `bytes`, if any.
+fn incomplete_utf8_suffix(bytes: &[u8]) -> usize {
+    for i in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - i];
+        if byte & 0xc0 != 0x80 {
+            let width = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            return if width > i { i } else { 0 };
+        }
+    }
+
+    0
+}
+
+/// Wraps the text written through it at `width` columns, breaking lines at spaces only, or
+/// passes it through untouched without a width.
+///
+/// A word wider than a whole line is given a line of its own rather than split.
+struct LineWrapper<W: Write> {
+    out: W,
+    width: Option<usize>,
+    /// Passes text through as it is, for preformatted text that must keep its lines.
+    verbatim: bool,
+    column: usize,
+    spaces: usize,
+    word: Vec<u8>,
+}
+
+impl<W: Write> LineWrapper<W> {
+    fn new(out: W, width: Option<usize>) -> Self {
+        Self {
+            out,
+            width: width.filter(|&width| width > 0),
+            verbatim: false,
+            column: 0,
+            spaces: 0,
+            word: Vec::new(),
+        }
+    }
+
+    /// Stops or resumes wrapping, keeping the text written so far on the lines it was given.
+    fn set_verbatim(&mut self, verbatim: bool) -> io::Result<()> {
+        self.write_word()?;
+        if self.spaces > 0 {
+            self.out.write_all(&b" ".repeat(self.spaces))?;
+            self.column += self.spaces;
+            self.spaces = 0;
+        }
+
+        self.verbatim = verbatim;
+        Ok(())
+    }
+
+    fn write_word(&mut self) -> io::Result<()> {
+        if self.word.is_empty() {
+            return Ok(());
+        }
+
+        // Counting the bytes that start a character gives the width of plain UTF-8 text.
+        let width = self.word.iter().filter(|&&b| b & 0xc0 != 0x80).count();
+        let max = self.width.unwrap_or(usize::MAX);
+        if self.column > 0 && self.column + self.spaces + width > max {
+            self.out.write_all(b"\n")?;
+            self.column = 0;
+        } else {
+            self.out.write_all(&b" ".repeat(self.spaces))?;
+            self.column += self.spaces;
+        }
+
+        self.spaces = 0;
+        self.column += width;
+        self.out.write_all(&mem::take(&mut self.word))
+    }
+}
+
+impl<W: Write> Write for LineWrapper<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.width.is_none() {
+            return self.out.write(buf);
+        }
+
+        if self.verbatim {
+            self.out.write_all(buf)?;
+            self.column = match buf.iter().rposition(|&b| b == b'\n') {
+                Some(i) => buf[i + 1..].iter().filter(|&&b| b & 0xc0 != 0x80).count(),
+                None => self.column + buf.iter().filter(|&&b| b & 0xc0 != 0x80).count(),
+            };
+            return Ok(buf.len());
+        }
+
+        for &b in buf {
+            match b {
+                b'\n' => {
+                    self.write_word()?;
+                    self.out.write_all(b"\n")?;
+                    self.column = 0;
+                    self.spaces = 0;
+                }
+                b' ' => {
+                    self.write_word()?;
+                    self.spaces += 1;
+                }
+                b => self.word.push(b),
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_word()?;
+        self.out.flush()
+    }
+}
+
+const INTERRUPTED_NOTICE: &str = "[connection interrupted]";
+
+fn show(body: impl Read, options: &Options, anchor: Option<&str>) -> io::Result<()> {
+    render(io::stdout().lock(), body, options, anchor)
+}
+
+/// Renders the HTML read from `body` as text to `out`, marking the element named by `anchor`.
+pub fn render(
+    mut out: impl Write,
+    mut body: impl Read,
+    options: &Options,
+    anchor: Option<&str>,
+) -> io::Result<()> {
+    let mut renderer = Renderer::new(&mut out, options.max_line_width);
+    renderer.title_heading = options.title_heading;
+    renderer.collapse_whitespace = !options.preserve_whitespace;
+    let anchor = anchor.filter(|anchor| !anchor.is_empty());
+    renderer.anchor = anchor.map(String::from);
+    let mut buf = [0u8; 4096];
+    let mut len = 0;
+    let mut interrupted = false;
+
+    loop {
+        let n = match body.read(&mut buf[len..]) {
+            Ok(n) => n,
+            Err(e) if options.lenient && Url::is_connection_lost(&e) => {
+                interrupted = true;
+                0
+            }
+            Err(e) => return Err(e),
+        };
+
+        if n == 0 {
+            renderer.feed(&String::from_utf8_lossy(&buf[..len]))?;
+            break;
+        }
+
+        // Hold back a character split across reads until the rest of it arrives.
+        len += n;
+        let complete = len - incomplete_utf8_suffix(&buf[..len]);
+        renderer.feed(&String::from_utf8_lossy(&buf[..complete]))?;
+
+        buf.copy_within(complete..len, 0);
+        len -= complete;
+    }
+
+    let anchor_found = renderer.anchor_found;
+    renderer.finish()?;
+    if let Some(anchor) = anchor.filter(|_| !anchor_found) {
+        eprintln!("anchor #{anchor} not found");
+    }
+
+    if interrupted {
+        writeln!(out, "\n{INTERRUPTED_NOTICE}")?;
+    }
+
+    out.flush()
+}
+
+const DIM: &str = "\x1b[2m";
+const HIGHLIGHT: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+fn show_source(body: &str, color: bool) {
+    // A final newline ends the last line rather than starting an empty one.
+    let body = body.strip_suffix('\n').unwrap_or(body);
+    if body.is_empty() {
+        return;
+    }
+
+    for (number, line) in (1..).zip(body.split('\n')) {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if color {
+            let line = line
+                .replace('<', &format!("{HIGHLIGHT}<{RESET}"))
+                .replace('>', &format!("{HIGHLIGHT}>{RESET}"));
+            println!("{DIM}{number:>6}{RESET} {line}");
+        } else {
+            println!("{number:>6} {line}");
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Options {
+    /// Writes the raw body to this file instead of rendering it to stdout.
+    pub output: Option<PathBuf>,
+    /// Prints the document `<title>` as a heading at the top of the rendered output.
+    pub title_heading: bool,
+    /// Prints whitespace in text as it appears in the source instead of collapsing it.
+    pub preserve_whitespace: bool,
+    /// Keeps whatever part of the body arrived when the connection fails midway.
+    pub lenient: bool,
+    /// Prints the request that would be sent instead of sending it.
+    pub print_request: bool,
+    /// Prints the final URL, status, type and size of the response instead of its body.
+    pub summary: bool,
+    /// Prints the [`Metrics`] of the fetch as JSON instead of the body.
+    pub metrics_json: bool,
+    /// Follows redirects from `https` to plain `http`, which are refused otherwise.
+    pub allow_downgrade: bool,
+    /// Follows a `<meta http-equiv="refresh">` of an HTML page as if it were a redirect.
+    pub follow_meta_refresh: bool,
+    /// Refuses redirects to another scheme, host or port than the ones of the first URL.
+    pub same_origin_redirects: bool,
+    /// Times a `429` or `503` response with a `Retry-After` is retried, two if unset.
+    pub retries: Option<usize>,
+    /// Wraps rendered text at this many columns, except within `<pre>` and the source shown for
+    /// `view-source:`. Zero leaves lines unwrapped.
+    pub max_line_width: Option<usize>,
+    /// Highlights the source with ANSI escapes, which are left out entirely when unset.
+    pub color: bool,
+    /// Bounds the time spent on the whole load, across redirects and retries.
+    pub deadline: Option<Duration>,
+    pub request: RequestOptions,
+}
+
+fn summarize(url: &Url, status: u16, headers: &Headers, mut body: impl Read) -> io::Result<()> {
+    let length = io::copy(&mut body, &mut io::sink())?;
+    let mut out = io::stdout().lock();
+    writeln!(out, "url: {url}")?;
+    writeln!(out, "status: {status}")?;
+    if let Some(content_type) = headers.get("content-type") {
+        writeln!(out, "content-type: {content_type}")?;
+    }
+    if let Some(content_length) = headers.get("content-length") {
+        writeln!(out, "content-length: {content_length}")?;
+    }
+    writeln!(out, "bytes: {length}")
+}
+
+/// Reads the rest of `body`, also returning whether it was cut short by its connection being
+/// lost, which `lenient` allows to be ignored.
+fn read_all(mut body: impl Read, lenient: bool) -> io::Result<(Vec<u8>, bool)> {
+    let mut content = Vec::new();
+    match body.read_to_end(&mut content) {
+        Ok(_) => Ok((content, false)),
+        Err(e) if lenient && Url::is_connection_lost(&e) => Ok((content, true)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies the rest of `body` to `out` like [`read_all`] reads it, only failures to read it being
+/// allowed to cut it short.
+fn copy_body(mut body: impl Read, mut out: impl Write, lenient: bool) -> io::Result<bool> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match body.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if lenient && Url::is_connection_lost(&e) => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        out.write_all(&buf[..n])?;
+    }
+
+    Ok(false)
+}
+
+fn save(body: impl Read, path: &Path, lenient: bool) -> io::Result<()> {
+    let file = fs::File::create(path)?;
+    if copy_body(body, file, lenient)? {
+        eprintln!("{}: {INTERRUPTED_NOTICE}", path.display());
+    }
+
+    Ok(())
+}
+
+pub fn load(url: Url, ctx: &mut RequestContext, options: &Options) -> Result<(), RequestError> {
+    let view_source = url.view_source();
+    if options.print_request {
+        print!("{}", url.build_request_with(&options.request, ctx)?);
+        return Ok(());
+    }
+
+    if options.metrics_json {
+        let metrics = fetch_metrics(url, ctx, options)?;
+        println!("{}", metrics.to_json());
+        if metrics.status >= 400 {
+            return Err(RequestError::Status(metrics.status));
+        }
+        return Ok(());
+    }
+
+    follow_redirects(url, ctx, options, |chain, response| {
+        let head = &chain[chain.len() - 1];
+        if options.summary {
+            summarize(head, response.status, &response.headers, response.body)?;
+        } else if let Some(output) = &options.output {
+            save(response.body, output, options.lenient)?;
+        } else if !response.is_text() {
+            let media_type = response
+                .content_type()
+                .map(|content_type| content_type.media_type)
+                .unwrap_or_default();
+            return Err(RequestError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Cannot display {media_type} content, save it with --output instead"),
+            )));
+        } else if view_source {
+            let (content, interrupted) = read_all(response.body, options.lenient)?;
+            show_source(&String::from_utf8_lossy(&content), options.color);
+            if interrupted {
+                writeln!(io::stdout().lock(), "{INTERRUPTED_NOTICE}")?;
+            }
+        } else if !response.is_html() {
+            // Only HTML has tags to strip, plain text, gemtext and the like read fine as they are.
+            let mut out = io::stdout().lock();
+            if copy_body(response.body, &mut out, options.lenient)? {
+                writeln!(out, "\n{INTERRUPTED_NOTICE}")?;
+            }
+        } else {
+            show(response.body, options, head.fragment())?;
+        }
+
+        if response.status >= 400 {
+            return Err(RequestError::Status(response.status));
+        }
+
+        Ok(())
+    })
+}
+
+/// Fetches `url` and returns the status, headers and body of the final response, following
+/// redirects and retries like [`load`] does.
+///
+/// The body is handed out as the bytes sent, only decompressed from its content-encoding, and
+/// isn't rendered in any way. Statuses of 400 and above are returned rather than failing.
+pub fn fetch_bytes(
+    url: Url,
+    ctx: &mut RequestContext,
+    options: &Options,
+) -> Result<(u16, Headers, Vec<u8>), RequestError> {
+    let (_, status, headers, body) = fetch_with_trace(url, ctx, options)?;
+    Ok((status, headers, body))
+}
+
+/// Works like [`fetch_bytes`], also returning the redirect chain that led to the response, from
+/// `url` itself to the URL the response came from.
+pub fn fetch_with_trace(
+    url: Url,
+    ctx: &mut RequestContext,
+    options: &Options,
+) -> Result<(Vec<Url>, u16, Headers, Vec<u8>), RequestError> {
+    follow_redirects(url, ctx, options, |chain, mut response| {
+        let mut body = Vec::new();
+        response.body.read_to_end(&mut body)?;
+        Ok((chain.to_vec(), response.status, response.headers, body))
+    })
+}
+
+/// What [`fetch_metrics`] measured while fetching a URL.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    pub final_url: Url,
+    pub status: u16,
+    pub redirect_count: usize,
+    /// Length of the final body once decoded.
+    pub content_length: u64,
+    /// From sending the first request until the final body was read.
+    pub total: Duration,
+    /// Added up over every request made, redirects and retries included.
+    pub timings: Timings,
+    /// Reading the final body.
+    pub transfer: Duration,
+}
+
+impl Metrics {
+    /// Formats the metrics as a single line JSON object, with times in milliseconds.
+    pub fn to_json(&self) -> String {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let url = self.final_url.to_string();
+        let mut final_url = String::with_capacity(url.len());
+        for c in url.chars() {
+            match c {
+                '"' | '\\' => {
+                    final_url.push('\\');
+                    final_url.push(c);
+                }
+                c if c.is_control() => write!(&mut final_url, "\\u{:04x}", c as u32).unwrap(),
+                c => final_url.push(c),
+            }
+        }
+
+        format!(
+            concat!(
+                r#"{{"final_url":"{}","status":{},"redirect_count":{},"content_length":{},"#,
+                r#""total_time_ms":{:.3},"resolve_ms":{:.3},"connect_ms":{:.3},"tls_ms":{:.3},"#,
+                r#""request_ms":{:.3},"ttfb_ms":{:.3},"transfer_ms":{:.3}}}"#,
+            ),
+            final_url,
+            self.status,
+            self.redirect_count,
+            self.content_length,
+            ms(self.total),
+            ms(self.timings.resolve),
+            ms(self.timings.connect),
+            ms(self.timings.tls_handshake),
+            ms(self.timings.request_write),
+            ms(self.timings.response_head),
+            ms(self.transfer),
+        )
+    }
+}
+
+/// Fetches `url` like [`fetch_bytes`] does, discarding the body and measuring where the time
+/// went instead.
+///
+/// The timings of `ctx` are reset first, see [`RequestContext::take_timings`].
+pub fn fetch_metrics(
+    url: Url,
+    ctx: &mut RequestContext,
+    options: &Options,
+) -> Result<Metrics, RequestError> {
+    let started = Instant::now();
+    ctx.take_timings();
+    let (chain, status, content_length, transfer) =
+        follow_redirects(url, ctx, options, |chain, mut response| {
+            let reading = Instant::now();
+            let content_length = io::copy(&mut response.body, &mut io::sink())?;
+            Ok((
+                chain.to_vec(),
+                response.status,
+                content_length,
+                reading.elapsed(),
+            ))
+        })?;
+
+    Ok(Metrics {
+        final_url: chain[chain.len() - 1].clone(),
+        status,
+        redirect_count: chain.len() - 1,
+        content_length,
+        total: started.elapsed(),
+        timings: ctx.take_timings(),
+        transfer,
+    })
+}
+
+/// Requests `url`, following redirects and retrying as `options` allow, and passes the final
+/// response to `handle` along with the redirect chain, which ends with the URL it came from.
+fn follow_redirects<T>(
+    url: Url,
+    ctx: &mut RequestContext,
+    options: &Options,
+    handle: impl FnOnce(&[Url], Response<'_>) -> Result<T, RequestError>,
+) -> Result<T, RequestError> {
+    // Everything the context does for this load, handling the response included, counts against
+    // its deadline.
+    let deadline = options.deadline.map(|budget| Instant::now() + budget);
+    ctx.set_deadline(deadline)?;
+    let result = follow_redirects_until(url, ctx, options, deadline, handle);
+    let reset = ctx.set_deadline(None);
+    let value = result?;
+    reset?;
+    Ok(value)
+}
+
+fn follow_redirects_until<T>(
+    url: Url,
+    ctx: &mut RequestContext,
+    options: &Options,
+    deadline: Option<Instant>,
+    handle: impl FnOnce(&[Url], Response<'_>) -> Result<T, RequestError>,
+) -> Result<T, RequestError> {
+    const MAX_REDIRECTS: usize = 10;
+    const MAX_QUERY_REDIRECTS: usize = 3;
+    const DEFAULT_RETRIES: usize = 2;
+    // Servers may ask for any wait, but nobody is watching a terminal for longer than this.
+    const MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+    // Pages refreshing later than this are meant to be read first.
+    const MAX_META_REFRESH_DELAY: u64 = 5;
+
+    let mut retries = options.retries.unwrap_or(DEFAULT_RETRIES);
+    let mut path = Vec::with_capacity(MAX_REDIRECTS);
+    path.push(url);
+
+    // Anything timing out past the deadline is reported as having run out of budget.
+    let timed_out = |e: io::Error| match deadline {
+        _ if DeadlinePassed::is(&e) => RequestError::DeadlineExceeded,
+        Some(deadline)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) && Instant::now() >= deadline =>
+        {
+            RequestError::DeadlineExceeded
+        }
+        _ => RequestError::Io(e),
+    };
+    let handle_error = |e| match e {
+        RequestError::Io(e) => timed_out(e),
+        e => e,
+    };
+
+    let mut request = options.request.clone();
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(RequestError::DeadlineExceeded);
+        }
+
+        let head = path.last().unwrap();
+        let mut response = match head.request(ctx, &request) {
+            Ok(response) => response,
+            Err(RequestError::Io(e)) => return Err(timed_out(e)),
+            Err(e) => return Err(e),
+        };
+        if let Some(wait) = response.retry_after().filter(|_| retries > 0) {
+            // The body is set aside first, so that the response can still be handled once the
+            // shared budget turns out to be spent.
+            let mut body = Vec::new();
+            response.body.read_to_end(&mut body).map_err(timed_out)?;
+            let (status, headers) = (response.status, mem::take(&mut response.headers));
+            drop(response);
+            if !ctx.take_retry() {
+                let body = Box::new(io::Cursor::new(body));
+                let response = Response {
+                    status,
+                    headers,
+                    body,
+                };
+                return handle(&path, response).map_err(handle_error);
+            }
+
+            retries -= 1;
+            let remaining = deadline.map_or(MAX_RETRY_WAIT, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            thread::sleep(wait.min(MAX_RETRY_WAIT).min(remaining));
+            continue;
+        }
+
+        // Local pages have nowhere to be redirected from, so their refreshes are only shown.
+        let refreshing = options.follow_meta_refresh
+            && response.status == 200
+            && response.is_html()
+            && RequestContext::addr(head).is_ok();
+        if !Response::is_redirect(response.status) && !refreshing {
+            return handle(&path, response).map_err(handle_error);
+        }
+
+        let location = if refreshing {
+            let mut body = Vec::new();
+            response.body.read_to_end(&mut body).map_err(timed_out)?;
+            let refresh = meta_refresh(&String::from_utf8_lossy(&body))
+                .filter(|&(delay, _)| delay <= MAX_META_REFRESH_DELAY);
+            let Some((_, location)) = refresh else {
+                let response = Response {
+                    body: Box::new(io::Cursor::new(body)),
+                    ..response
+                };
+                return handle(&path, response).map_err(handle_error);
+            };
+            location
+        } else {
+            match response.headers.remove("location") {
+                Some(location) => location,
+                None => return Err(RequestError::InvalidLocation(path, None)),
+            }
+        };
+
+        // Only 307 and 308 ask for the method and body to be kept when following, and a refresh
+        // is a plain navigation.
+        if refreshing || matches!(response.status, 301..=303) {
+            request = RequestOptions {
+                version: request.version,
+                headers: mem::take(&mut request.headers),
+                ..RequestOptions::default()
+            };
+        }
+
+        let follower = match head.follow(location.clone()) {
+            Ok(follower) => follower,
+            Err(_) => return Err(RequestError::InvalidLocation(path, Some(location))),
+        };
+        let normalized = follower.normalized();
+        let cycle = path.iter().any(|url| url.normalized() == normalized);
+        let resource = normalized.without_query();
+        let downgrade = head.scheme() == "https" && follower.scheme() == "http";
+        let cross_origin = !path[0].same_origin(&follower);
+        path.push(follower);
+
+        if downgrade && !options.allow_downgrade {
+            return Err(RequestError::InsecureRedirect(path));
+        }
+
+        if cross_origin && options.same_origin_redirects {
+            return Err(RequestError::CrossOriginRedirect(path));
+        }
+
+        if cycle {
+            return Err(RequestError::RedirectCycle(path));
+        }
+
+        let query_redirects = path
+            .iter()
+            .rev()
+            .take_while(|url| url.normalized().without_query() == resource)
+            .count();
+        if query_redirects > MAX_QUERY_REDIRECTS {
+            return Err(RequestError::RedirectQueryLoop(path));
+        }
+
+        if path.len() >= MAX_REDIRECTS {
+            return Err(RequestError::TooManyRedirects(path));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// An address nothing listens on, so that connecting to it is refused.
+    fn closed_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn connects_to_the_next_address_when_one_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = [closed_addr(), listener.local_addr().unwrap()];
+
+        let ctx = RequestContext::default();
+        let stream = ctx.connect_any(&(String::from("example.com"), 80), &addrs);
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), addrs[1]);
+    }
+
+    #[test]
+    fn lists_every_address_tried_when_all_fail() {
+        let addrs = [closed_addr(), closed_addr()];
+
+        let ctx = RequestContext::default();
+        let error = ctx
+            .connect_any(&(String::from("example.com"), 80), &addrs)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+
+        let message = error.to_string();
+        assert!(message.starts_with("Could not connect to example.com:80, tried "));
+        assert!(addrs.iter().all(|addr| message.contains(&addr.to_string())));
+    }
+}
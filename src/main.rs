@@ -1,418 +1,417 @@
 use std::{
-    collections::HashMap,
     env,
-    fmt::Write as _,
-    fs,
-    io::{BufRead, BufReader, Read, Write},
-    net::TcpStream,
+    error::Error,
+    fmt, fs,
+    io::{self, IsTerminal, Read},
     path::PathBuf,
-    sync::Arc,
+    process,
+    time::Duration,
 };
 
-use flate2::bufread::GzDecoder;
-use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use vanadium::{
+    load, Method, Options, Proxy, RequestContext, RequestError, ResolveError, Url, UrlParseError,
+    Version,
+};
 
-pub enum RequestStream {
-    Tcp(TcpStream),
-    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+/// Exit codes reported for each category of failure, kept stable for scripts.
+///
+/// | Code | Failure                                              |
+/// |------|------------------------------------------------------|
+/// | 1    | Anything not listed below, including bad usage       |
+/// | 2    | The URL could not be parsed                          |
+/// | 3    | The host name could not be resolved                  |
+/// | 4    | The connection was refused                           |
+/// | 5    | The lookup, connection or overall deadline timed out |
+/// | 6    | The TLS handshake or session failed                  |
+/// | 7    | The redirect chain was cyclic, too long or insecure  |
+/// | 8    | The server answered with a 4xx status                |
+/// | 9    | The server answered with a 5xx status                |
+///
+/// When several URLs fail, the code of the first failure is reported.
+mod exit_code {
+    pub const FAILURE: i32 = 1;
+    pub const URL_PARSE: i32 = 2;
+    pub const RESOLVE: i32 = 3;
+    pub const CONNECTION_REFUSED: i32 = 4;
+    pub const TIMED_OUT: i32 = 5;
+    pub const TLS: i32 = 6;
+    pub const REDIRECT: i32 = 7;
+    pub const CLIENT_ERROR: i32 = 8;
+    pub const SERVER_ERROR: i32 = 9;
 }
 
-impl Read for RequestStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self {
-            RequestStream::Tcp(s) => s.read(buf),
-            RequestStream::Tls(s) => s.read(buf),
-        }
-    }
+fn fail(message: impl fmt::Display) -> ! {
+    eprintln!("vanadium: {message}");
+    process::exit(exit_code::FAILURE);
 }
 
-impl Write for RequestStream {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self {
-            RequestStream::Tcp(s) => s.write(buf),
-            RequestStream::Tls(s) => s.write(buf),
-        }
+fn exit_code(error: &(dyn Error + 'static)) -> i32 {
+    if error.is::<UrlParseError>() {
+        return exit_code::URL_PARSE;
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        match self {
-            RequestStream::Tcp(s) => s.flush(),
-            RequestStream::Tls(s) => s.flush(),
+    let Some(error) = error.downcast_ref::<RequestError>() else {
+        return exit_code::FAILURE;
+    };
+
+    match error {
+        RequestError::Io(e) => {
+            let inner = e.get_ref();
+            // A lookup that timed out says nothing of whether the host exists.
+            if inner.is_some_and(|inner| inner.is::<ResolveError>())
+                && e.kind() != io::ErrorKind::TimedOut
+            {
+                return exit_code::RESOLVE;
+            }
+            if inner.is_some_and(|inner| inner.is::<rustls::Error>()) {
+                return exit_code::TLS;
+            }
+
+            match e.kind() {
+                io::ErrorKind::ConnectionRefused => exit_code::CONNECTION_REFUSED,
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => exit_code::TIMED_OUT,
+                _ => exit_code::FAILURE,
+            }
         }
+        RequestError::RedirectCycle(_)
+        | RequestError::RedirectQueryLoop(_)
+        | RequestError::TooManyRedirects(_)
+        | RequestError::InsecureRedirect(_)
+        | RequestError::CrossOriginRedirect(_)
+        | RequestError::InvalidLocation(..) => exit_code::REDIRECT,
+        RequestError::DeadlineExceeded => exit_code::TIMED_OUT,
+        RequestError::Status(400..=499) => exit_code::CLIENT_ERROR,
+        RequestError::Status(_) => exit_code::SERVER_ERROR,
     }
 }
 
-#[derive(Default)]
-pub struct RequestContext {
-    inner: HashMap<(String, u16), BufReader<RequestStream>>,
+/// The proxies to fetch through, looked up in the environment only once a URL of their scheme is
+/// fetched, so that a bad variable for a scheme never used goes unnoticed.
+struct Proxies {
+    /// The `--proxy`, which applies to every scheme in place of the variables.
+    proxy: Option<String>,
+    http_configured: bool,
+    https_configured: bool,
 }
 
-impl RequestContext {
-    fn build_reader(url: &Url) -> BufReader<RequestStream> {
-        match url {
-            Url::Http { addr, .. } => {
-                let s = TcpStream::connect(addr).unwrap();
-                BufReader::new(RequestStream::Tcp(s))
-            }
-            Url::Https { addr, .. } => {
-                let s = TcpStream::connect(addr).unwrap();
-                let root_store = webpki_roots::TLS_SERVER_ROOTS
-                    .iter()
-                    .cloned()
-                    .collect::<RootCertStore>();
-                let config = ClientConfig::builder()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth();
-
-                let hostname = ServerName::try_from(addr.0.clone()).unwrap();
-                let client = ClientConnection::new(Arc::new(config), hostname).unwrap();
-                BufReader::new(RequestStream::Tls(Box::new(StreamOwned::new(client, s))))
-            }
-            _ => unreachable!(),
+impl Proxies {
+    fn new(proxy: Option<String>) -> Self {
+        Self {
+            proxy,
+            http_configured: false,
+            https_configured: false,
         }
     }
 
-    pub fn stream(&mut self, url: &Url) -> &mut RequestStream {
-        self.reader(url).get_mut()
-    }
-
-    pub fn reader(&mut self, url: &Url) -> &mut BufReader<RequestStream> {
-        let (Url::Http { addr, .. } | Url::Https { addr, .. }) = url else {
-            panic!("Unsupported variant in this context: {url:?}");
+    /// Sets up `ctx` with the proxy for the scheme of `url`, failing for this URL alone if the
+    /// proxy is invalid.
+    fn configure(&mut self, url: &Url, ctx: &mut RequestContext) -> io::Result<()> {
+        let first = !self.http_configured && !self.https_configured;
+        let (configured, names) = match url {
+            Url::Http { .. } => (&mut self.http_configured, ["http_proxy", "HTTP_PROXY"]),
+            Url::Https { .. } | Url::Gemini { .. } => {
+                (&mut self.https_configured, ["https_proxy", "HTTPS_PROXY"])
+            }
+            _ => return Ok(()),
         };
+        if *configured {
+            return Ok(());
+        }
 
-        if !self.inner.contains_key(addr) {
-            self.inner.insert(addr.clone(), Self::build_reader(url));
+        if first {
+            if let Some(hosts) = env_var(&["no_proxy", "NO_PROXY"]) {
+                ctx.set_no_proxy(&hosts);
+            }
+        }
+
+        if let Some(value) = self.proxy.clone().or_else(|| env_var(&names)) {
+            let proxy = Proxy::parse(&value).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid proxy {value}: {e}"),
+                )
+            })?;
+            match url {
+                Url::Http { .. } => ctx.set_http_proxy(proxy),
+                _ => ctx.set_https_proxy(proxy),
+            }
         }
 
-        self.inner.get_mut(addr).unwrap()
+        *configured = true;
+        Ok(())
     }
 }
 
-pub enum Response {
-    Ok(String),
-    Redirect(String),
+/// When to highlight output with ANSI escapes, as chosen with `--color`.
+#[derive(Clone, Copy)]
+enum Color {
+    Auto,
+    Always,
+    Never,
 }
 
-impl Response {
-    pub const fn is_redirect(status: u16) -> bool {
-        matches!(status, 301 | 302 | 303 | 307 | 308)
+impl Color {
+    /// Resolves the choice against `NO_COLOR`, which only overrides `auto`, and whether stdout is
+    /// a terminal.
+    fn enabled(self) -> bool {
+        match self {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => env_var(&["NO_COLOR"]).is_none() && io::stdout().is_terminal(),
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum Url {
-    Http {
-        view_source: bool,
-        addr: (String, u16),
-        path: PathBuf,
-    },
-    Https {
-        view_source: bool,
-        addr: (String, u16),
-        path: PathBuf,
-    },
-    File {
-        view_source: bool,
-        path: PathBuf,
-    },
-    Data {
-        view_source: bool,
-        media_type: String,
-        content: String,
-    },
+/// Reads the first of the environment variables `names` that is set and not empty.
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|value| !value.is_empty()))
 }
 
-impl Url {
-    pub fn new(url: &str) -> Self {
-        let view_source = url.starts_with("view-source:");
-        let url = url.strip_prefix("view-source:").unwrap_or(url);
-
-        if url.starts_with("data:") {
-            let (media_type, content) = url.strip_prefix("data:").unwrap().split_once(',').unwrap();
-            return Self::Data {
-                view_source,
-                media_type: media_type.to_string(),
-                content: content.to_string(),
-            };
-        }
-
-        let (scheme, url) = url.split_once("://").unwrap();
-        if scheme == "file" {
-            return Self::File {
-                view_source,
-                path: PathBuf::from(url),
-            };
-        }
+/// Returns the width of the terminal, or 80 columns if it doesn't tell.
+fn terminal_width() -> usize {
+    // Terminals export their width as COLUMNS, which is also how to override it.
+    env_var(&["COLUMNS"])
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
 
-        let mut remainder = url.to_string();
-        if !remainder.contains('/') {
-            remainder.push('/');
-        }
+fn number_arg(flag: &str, value: Option<String>) -> u64 {
+    let value = value.unwrap_or_else(|| fail(format!("{flag} requires a number")));
+    value
+        .parse()
+        .unwrap_or_else(|_| fail(format!("{flag}: invalid number: {value}")))
+}
 
-        let (mut host, path) = remainder.split_once('/').unwrap();
-        let mut port = match scheme {
-            "http" => 80,
-            "https" => 443,
-            _ => panic!("Unsupported scheme: {scheme}"),
-        };
+/// Reads one URL per line from `path`, or from stdin if it is `-`, skipping blank lines and `#`
+/// comments.
+fn read_urls(path: &str) -> io::Result<Vec<String>> {
+    let content = if path == "-" {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        fs::read_to_string(path)?
+    };
+
+    let urls = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+
+    Ok(urls)
+}
 
-        if host.contains(':') {
-            let addr = host.split_once(':').unwrap();
-            host = addr.0;
-            port = addr.1.parse().unwrap();
+/// Reads a request body given like curl's `--data`: `@path` for the bytes of a file, `@-` for
+/// stdin, and anything else as the body itself.
+fn read_data(value: &str) -> io::Result<Vec<u8>> {
+    match value.strip_prefix('@') {
+        Some("-") => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Ok(data)
         }
+        Some(path) => fs::read(path),
+        None => Ok(value.as_bytes().to_vec()),
+    }
+}
 
-        match scheme {
-            "http" => Self::Http {
-                view_source,
-                addr: (host.to_string(), port),
-                path: PathBuf::from(format!("/{path}")),
-            },
-            "https" => Self::Https {
-                view_source,
-                addr: (host.to_string(), port),
-                path: PathBuf::from(format!("/{path}")),
+fn fetch(
+    url: &str,
+    ctx: &mut RequestContext,
+    proxies: &mut Proxies,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let url = Url::new(url)?;
+    proxies.configure(&url, ctx)?;
+    Ok(load(url, ctx, options)?)
+}
+
+fn main() {
+    let mut options = Options::default();
+    let mut verbose = false;
+    let mut trace = false;
+    let mut insecure = false;
+    let mut ca_files = Vec::new();
+    let mut max_decoded_size = None;
+    let mut max_decode_ratio = None;
+    let mut max_connections = None;
+    let mut connect_attempts = None;
+    let mut retry_budget = None;
+    let mut connect_timeout = None;
+    let mut host_sni = false;
+    let mut proxy = None;
+    let mut color = Color::Auto;
+    let mut urls = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                let path = args
+                    .next()
+                    .unwrap_or_else(|| fail("--output requires a path"));
+                options.output = Some(PathBuf::from(path));
+            }
+            "-i" | "--input-file" => {
+                let path = args
+                    .next()
+                    .unwrap_or_else(|| fail("--input-file requires a path"));
+                let lines = read_urls(&path).unwrap_or_else(|e| fail(format!("{path}: {e}")));
+                urls.extend(lines);
+            }
+            "-" => {
+                let lines = read_urls("-").unwrap_or_else(|e| fail(format!("stdin: {e}")));
+                urls.extend(lines);
+            }
+            "--max-decoded-size" => max_decoded_size = Some(number_arg(&arg, args.next())),
+            "--max-decode-ratio" => max_decode_ratio = Some(number_arg(&arg, args.next())),
+            "--cacert" => {
+                let path = args
+                    .next()
+                    .unwrap_or_else(|| fail("--cacert requires a path"));
+                ca_files.push(PathBuf::from(path));
+            }
+            "-d" | "--data" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| fail("--data requires a body, @file or @-"));
+                let data = read_data(&value).unwrap_or_else(|e| fail(format!("{value}: {e}")));
+                options.request.method = Method::Post;
+                options.request.body = Some(data);
+                options
+                    .request
+                    .content_type
+                    .get_or_insert_with(|| String::from("application/x-www-form-urlencoded"));
+            }
+            "-0" | "--http1.0" => options.request.version = Version::Http10,
+            "--max-connections" => max_connections = Some(number_arg(&arg, args.next())),
+            "--allow-downgrade" => options.allow_downgrade = true,
+            "--follow-meta-refresh" => options.follow_meta_refresh = true,
+            "--same-origin-redirects" => options.same_origin_redirects = true,
+            "--retry" => options.retries = Some(number_arg(&arg, args.next()) as usize),
+            "--retry-budget" => retry_budget = Some(number_arg(&arg, args.next())),
+            "--preserve-whitespace" => options.preserve_whitespace = true,
+            "--print-request" => options.print_request = true,
+            "--summary" => options.summary = true,
+            "--metrics-json" => options.metrics_json = true,
+            "--connect-attempts" => connect_attempts = Some(number_arg(&arg, args.next())),
+            "--proxy" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| fail("--proxy requires an address"));
+                if let Err(e) = Proxy::parse(&value) {
+                    fail(format!("invalid proxy {value}: {e}"));
+                }
+                proxy = Some(value);
+            }
+            "--host" => {
+                let host = args
+                    .next()
+                    .unwrap_or_else(|| fail("--host requires a host name"));
+                options.request.host = Some(host);
+            }
+            // Also verifies the certificate against the --host name over TLS.
+            "--host-sni" => host_sni = true,
+            "--color" => {
+                color = match args.next().as_deref() {
+                    Some("auto") => Color::Auto,
+                    Some("always") => Color::Always,
+                    Some("never") => Color::Never,
+                    _ => fail("--color requires one of auto, always or never"),
+                };
+            }
+            "--connect-timeout" => match number_arg(&arg, args.next()) {
+                0 => fail(format!("{arg}: must be at least 1")),
+                seconds => connect_timeout = Some(Duration::from_secs(seconds)),
             },
-            _ => panic!("Unsupported scheme: {scheme}"),
+            "-m" | "--max-time" => {
+                let seconds = number_arg(&arg, args.next());
+                options.deadline = Some(Duration::from_secs(seconds));
+            }
+            "--max-line-width" => {
+                let width = match args.next() {
+                    Some(width) if width == "auto" => terminal_width(),
+                    width => number_arg(&arg, width) as usize,
+                };
+                options.max_line_width = Some(width);
+            }
+            "--title" => options.title_heading = true,
+            "--lenient" => options.lenient = true,
+            "-v" | "--verbose" => verbose = true,
+            "--trace" => trace = true,
+            // Only for testing against local servers with self-signed certificates.
+            "-k" | "--insecure" => insecure = true,
+            _ => urls.push(arg),
         }
     }
 
-    pub const fn view_source(&self) -> bool {
-        match self {
-            Url::Http { view_source, .. }
-            | Url::Https { view_source, .. }
-            | Url::File { view_source, .. }
-            | Url::Data { view_source, .. } => *view_source,
-        }
+    // Text shown on a terminal is wrapped to fit it unless told otherwise, but not when piped.
+    if options.max_line_width.is_none() && io::stdout().is_terminal() {
+        options.max_line_width = Some(terminal_width());
     }
 
-    fn display_host(&self) -> String {
-        match self {
-            Url::Http { addr: (h, 80), .. } | Url::Https { addr: (h, 443), .. } => h.to_string(),
-            Url::Http { addr: (h, p), .. } | Url::Https { addr: (h, p), .. } => format!("{h}:{p}"),
-            _ => panic!("Network address is only available for http/https variants"),
-        }
+    if urls.is_empty() && !io::stdin().is_terminal() {
+        urls = read_urls("-").unwrap_or_else(|e| fail(format!("stdin: {e}")));
+    } else if urls.is_empty() {
+        urls.push(String::from("file:///Users/mbrdg/Code/vanadium/README.md"));
     }
 
-    pub fn request(&self, ctx: &mut RequestContext) -> Response {
-        if let Self::File { path, .. } = self {
-            let content = fs::read_to_string(path).unwrap();
-            return Response::Ok(content);
-        }
+    options.color = color.enabled();
 
-        if let Self::Data { content, .. } = self {
-            return Response::Ok(content.to_string());
+    let mut ctx = RequestContext::default();
+    ctx.set_verbose(verbose);
+    ctx.set_trace(trace);
+    ctx.set_insecure(insecure);
+    for path in &ca_files {
+        if let Err(e) = ctx.add_pem_roots(path) {
+            fail(format!("{}: {e}", path.display()));
         }
-
-        let (Self::Http { path, .. } | Self::Https { path, .. }) = self else {
-            panic!("Network path is only available for http/https variants")
-        };
-
-        let mut request = String::new();
-        let version = env!("CARGO_PKG_VERSION");
-        write!(&mut request, "GET {} HTTP/1.1\r\n", path.display()).unwrap();
-        write!(&mut request, "Host: {}\r\n", self.display_host()).unwrap();
-        write!(&mut request, "Connection: keep-alive\r\n").unwrap();
-        write!(&mut request, "Accept-Encoding: gzip\r\n").unwrap();
-        write!(&mut request, "User-Agent: vanadium/{version}\r\n").unwrap();
-        write!(&mut request, "\r\n").unwrap();
-
-        let s = ctx.stream(self);
-        s.write_all(request.as_bytes()).unwrap();
-
-        let response = ctx.reader(self);
-        Url::read_response(response)
     }
-
-    fn read_response(reader: &mut BufReader<RequestStream>) -> Response {
-        let mut statusline = String::new();
-        reader.read_line(&mut statusline).unwrap();
-
-        let mut parts = statusline.splitn(3, ' ');
-        let _version = parts.next().unwrap();
-        let status = parts.next().unwrap().parse().unwrap();
-        let _explanation = parts.next().unwrap();
-
-        let mut response_headers = HashMap::new();
-        loop {
-            let mut line = String::new();
-            reader.read_line(&mut line).unwrap();
-            if line.trim_end().is_empty() {
-                break;
-            }
-
-            let (header, value) = line.split_once(':').unwrap();
-            response_headers.insert(header.to_lowercase(), value.trim().to_string());
-        }
-
-        let content = if response_headers
-            .get("transfer-encoding")
-            .is_some_and(|v| v == "chunked")
-        {
-            debug_assert!(!response_headers.contains_key("content-length"));
-            Url::read_chunks(reader)
-        } else {
-            let content_length = response_headers
-                .get("content-length")
-                .expect("Missing content-length header in HTTP response")
-                .parse::<usize>()
-                .unwrap();
-            let mut content = vec![0u8; content_length];
-            reader.read_exact(&mut content).unwrap();
-            content
-        };
-
-        if Response::is_redirect(status) {
-            let location = response_headers
-                .get("location")
-                .expect("Missing location header in HTTP response")
-                .to_string();
-            return Response::Redirect(location);
-        }
-
-        let body = if response_headers
-            .get("content-encoding")
-            .is_some_and(|v| v == "gzip")
-        {
-            let mut decoder = GzDecoder::new(&content[..]);
-            let mut content = String::new();
-            decoder.read_to_string(&mut content).unwrap();
-            content
-        } else {
-            String::from_utf8(content).unwrap()
-        };
-
-        Response::Ok(body)
+    if let Some(max) = max_decoded_size {
+        ctx.set_max_decoded_size(max);
     }
-
-    fn read_chunks(reader: &mut BufReader<RequestStream>) -> Vec<u8> {
-        let mut content = Vec::new();
-        loop {
-            let mut lengthline = String::new();
-            reader.read_line(&mut lengthline).unwrap();
-
-            let chunk_length = usize::from_str_radix(lengthline.trim_end(), 16).unwrap();
-            let mut chunk = vec![0u8; chunk_length];
-            reader.read_exact(&mut chunk).unwrap();
-
-            let mut terminator = String::with_capacity(2);
-            reader.read_line(&mut terminator).unwrap();
-            debug_assert_eq!(terminator, "\r\n");
-
-            content.extend(chunk);
-            if chunk_length == 0 {
-                break;
-            }
-        }
-
-        content
+    if let Some(max) = max_decode_ratio {
+        ctx.set_max_decode_ratio(max);
     }
-
-    pub fn follow(&self, location: String) -> Self {
-        match self {
-            Url::Http { .. } | Url::Https { .. } if !location.starts_with('/') => {
-                Url::new(&location)
-            }
-            Url::Http { addr, .. } => Url::Http {
-                view_source: false,
-                addr: addr.clone(),
-                path: PathBuf::from(location),
-            },
-            Url::Https { addr, .. } => Url::Https {
-                view_source: false,
-                addr: addr.clone(),
-                path: PathBuf::from(location),
-            },
-            _ => panic!("Link following can only be called for http/https variants"),
-        }
+    if let Some(max) = max_connections {
+        ctx.set_max_connections(max as usize);
     }
-}
-
-enum EntityReadError {
-    Eof,
-    Unsupported(usize),
-}
-
-fn read_entity(body: &str) -> Result<(usize, &'static str), EntityReadError> {
-    assert!(body.starts_with('&'));
-    match body.find(';') {
-        Some(i) => match &body[1..i] {
-            "lt" => Ok((i - 1, "<")),
-            "gt" => Ok((i - 1, ">")),
-            _ => Err(EntityReadError::Unsupported(i)),
-        },
-        None => Err(EntityReadError::Eof),
+    if let Some(attempts) = connect_attempts {
+        ctx.set_connect_attempts(attempts as usize);
     }
-}
-
-fn show(body: &str) {
-    let mut chars = body.char_indices();
-    let mut in_tag = false;
-
-    while let Some((i, c)) = chars.next() {
-        if c == '<' {
-            in_tag = true;
-        } else if c == '>' {
-            in_tag = false;
-        } else if c == '&' {
-            match read_entity(&body[i..]) {
-                Ok((j, entity)) => {
-                    print!("{entity}");
-                    chars.nth(j);
-                }
-                Err(EntityReadError::Eof) => {
-                    print!("{}", &body[i..]);
-                    chars.nth(body[i..].len());
-                }
-                Err(EntityReadError::Unsupported(j)) => {
-                    print!("{}", &body[i..=(i + j)]);
-                    chars.nth(j);
-                }
-            }
-        } else if !in_tag {
-            print!("{c}");
-        }
+    if let Some(budget) = retry_budget {
+        ctx.set_retry_budget(budget as usize);
     }
-}
-
-fn show_source(body: &str) {
-    for (number, line) in (1..).zip(body.lines()) {
-        println!("{number:>6} {line}");
+    ctx.set_connect_timeout(connect_timeout);
+    if host_sni {
+        // A Host header may carry a port, which has no place in the server name.
+        let host = options.request.host.as_deref();
+        let name = host.map(|host| host.rsplit_once(':').map_or(host, |(name, _)| name));
+        ctx.set_server_name(name.map(String::from));
     }
-}
 
-fn load(url: Url, ctx: &mut RequestContext) {
-    const MAX_REDIRECTS: usize = 10;
+    let mut proxies = Proxies::new(proxy);
 
-    let view_source = url.view_source();
-
-    let mut path = Vec::with_capacity(MAX_REDIRECTS);
-    path.push(url);
-
-    loop {
-        let head = path.last().unwrap();
-        match head.request(ctx) {
-            Response::Ok(body) if view_source => return show_source(&body),
-            Response::Ok(body) => return show(&body),
-            Response::Redirect(location) => {
-                let follower = head.follow(location);
-                assert!(!path.contains(&follower), "Redirection chain has a cycle");
+    // A failing URL is reported without stopping the ones after it.
+    let mut code = None;
+    for (i, url) in urls.iter().enumerate() {
+        if urls.len() > 1 {
+            let separator = if i == 0 { "" } else { "\n" };
+            println!("{separator}==> {url} <==");
+        }
 
-                path.push(follower);
-                assert!(path.len() < MAX_REDIRECTS, "Too many redirects");
-            }
+        if let Err(e) = fetch(url, &mut ctx, &mut proxies, &options) {
+            eprintln!("vanadium: {url}: {e}");
+            code = code.or(Some(exit_code(e.as_ref())));
         }
     }
-}
-
-fn main() {
-    let args = env::args().collect::<Vec<_>>();
-    let url = args.get(1).map_or(
-        "file:///Users/mbrdg/Code/vanadium/README.md",
-        String::as_str,
-    );
 
-    let mut ctx = RequestContext::default();
-    load(Url::new(url), &mut ctx);
+    drop(ctx);
+    if let Some(code) = code {
+        process::exit(code);
+    }
 }
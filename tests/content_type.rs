@@ -0,0 +1,35 @@
+use vanadium::{ContentType, RequestContext, RequestOptions, Url};
+
+#[test]
+fn parses_media_type_and_charset() {
+    let content_type = ContentType::parse("Text/HTML;  Charset=UTF-8 ").unwrap();
+    assert_eq!(content_type.media_type, "text/html");
+    assert_eq!(content_type.parameter("charset"), Some("UTF-8"));
+}
+
+#[test]
+fn unquotes_boundary() {
+    let content_type =
+        ContentType::parse(r#"multipart/form-data; boundary="a; b=\"c\""; x=1"#).unwrap();
+    assert_eq!(content_type.media_type, "multipart/form-data");
+    assert_eq!(content_type.parameter("boundary"), Some(r#"a; b="c""#));
+    assert_eq!(content_type.parameter("x"), Some("1"));
+}
+
+#[test]
+fn defaults_data_urls_to_plain_text() {
+    let mut ctx = RequestContext::default();
+    let types = [
+        ("data:,hello", "text/plain", Some("US-ASCII")),
+        ("data:;charset=UTF-8,hello", "text/plain", Some("UTF-8")),
+        ("data:text/html,<b>hi</b>", "text/html", None),
+    ];
+
+    for (url, media_type, charset) in types {
+        let url = Url::new(url).unwrap();
+        let response = url.request(&mut ctx, &RequestOptions::default()).unwrap();
+        let content_type = response.content_type().unwrap();
+        assert_eq!(content_type.media_type, media_type, "{url}");
+        assert_eq!(content_type.parameter("charset"), charset, "{url}");
+    }
+}
@@ -0,0 +1,53 @@
+use vanadium::{decode_entities, Options};
+
+/// Renders `html` as shown for a page without a fragment.
+fn render(html: &str) -> String {
+    render_at(html, None)
+}
+
+/// Renders `html` with the default options, marking the element named by `anchor`.
+fn render_at(html: &str, anchor: Option<&str>) -> String {
+    let mut out = Vec::new();
+    vanadium::render(&mut out, html.as_bytes(), &Options::default(), anchor).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn decodes_known_entities_only() {
+    let text = "&lt;p&gt; &amp;amp; &#65;&#x42; &bogus; &lt &#xD800; a & b";
+    assert_eq!(
+        decode_entities(text),
+        "<p> &amp; AB &bogus; &lt &#xD800; a & b"
+    );
+}
+
+#[test]
+fn keeps_unterminated_entities_at_the_end() {
+    assert_eq!(decode_entities("x&"), "x&");
+    assert_eq!(decode_entities("x&amp"), "x&amp");
+    assert_eq!(decode_entities("&lt;"), "<");
+}
+
+#[test]
+fn ends_comments_only_at_their_closing_delimiter() {
+    assert_eq!(render("<p>a<!-- a > b -->b</p>"), "ab\n");
+    assert_eq!(render("a<!---->b"), "ab\n");
+}
+
+#[test]
+fn drops_a_comment_left_open_at_the_end() {
+    assert_eq!(render("a<!-- never closed"), "a\n");
+}
+
+#[test]
+fn keeps_a_stray_comment_end_as_text() {
+    assert_eq!(render("a --> b"), "a --> b\n");
+}
+
+#[test]
+fn marks_the_element_named_by_the_anchor() {
+    let html = r#"<p id="top">a</p><h2 id="x">Two</h2><a name="y">Y</a>"#;
+    assert_eq!(render_at(html, Some("x")), "a\n[#x]Two\nY\n");
+    assert_eq!(render_at(html, Some("y")), "a\nTwo\n[#y]Y\n");
+    assert_eq!(render_at(html, Some("missing")), render(html));
+}
@@ -0,0 +1,313 @@
+use std::{env, fs, io, process};
+
+use vanadium::{RequestContext, RequestOptions, Url, UrlParseError};
+
+#[test]
+fn displays_the_url_it_was_parsed_from() {
+    let urls = [
+        "http://example.com/",
+        "http://example.com:8080/a/b?q=1#top",
+        "https://example.com/index.html",
+        "https://example.com:8443/",
+        "file:///tmp/notes.txt",
+        "data:text/html,<b>hi</b>",
+        "view-source:http://example.com/",
+    ];
+
+    for url in urls {
+        assert_eq!(Url::new(url).unwrap().to_string(), url);
+    }
+}
+
+#[test]
+fn normalized_ignores_view_source() {
+    let url = Url::new("http://example.com/").unwrap();
+    let source = Url::new("view-source:http://example.com/").unwrap();
+    assert_ne!(url, source);
+    assert_eq!(url.normalized(), source.normalized());
+}
+
+#[test]
+fn requests_a_single_leading_slash() {
+    let targets = [
+        ("http://example.com", "/"),
+        ("http://example.com/", "/"),
+        ("http://example.com//double", "/double"),
+        ("http://example.com?q=1", "/?q=1"),
+        ("http://example.com:8080", "/"),
+    ];
+
+    for (url, target) in targets {
+        let request = Url::new(url)
+            .unwrap()
+            .build_request(&RequestOptions::default())
+            .unwrap();
+        assert!(
+            request.starts_with(&format!("GET {target} HTTP/1.1\r\n")),
+            "{url}: {request}"
+        );
+    }
+}
+
+#[test]
+fn names_only_non_default_ports_in_host_header() {
+    let hosts = [
+        ("http://example.com/", "example.com"),
+        ("http://example.com:80/", "example.com"),
+        ("http://example.com:8080/", "example.com:8080"),
+        ("https://example.com/", "example.com"),
+        ("https://example.com:443/", "example.com"),
+        ("https://example.com:8443/", "example.com:8443"),
+    ];
+
+    for (url, host) in hosts {
+        let request = Url::new(url)
+            .unwrap()
+            .build_request(&RequestOptions::default())
+            .unwrap();
+        assert!(
+            request.contains(&format!("\r\nHost: {host}\r\n")),
+            "{url}: {request}"
+        );
+    }
+}
+
+#[test]
+fn removes_dot_segments_from_request_target() {
+    let targets = [
+        ("http://example.com/a/b/../c", "/a/c"),
+        ("http://example.com/a/./b", "/a/b"),
+        ("http://example.com/a/b/../../../c", "/c"),
+        ("http://example.com/./x", "/x"),
+        ("http://example.com/a/b/..", "/a/"),
+        ("http://example.com/a/../b?next=../c", "/b?next=../c"),
+    ];
+
+    for (url, target) in targets {
+        let request = Url::new(url)
+            .unwrap()
+            .build_request(&RequestOptions::default())
+            .unwrap();
+        assert!(
+            request.starts_with(&format!("GET {target} HTTP/1.1\r\n")),
+            "{url}: {request}"
+        );
+    }
+}
+
+#[test]
+fn joins_references_like_rfc_3986() {
+    // The normal and abnormal examples of RFC 3986 5.4, but for those with another scheme.
+    let base = Url::new("http://a/b/c/d;p?q").unwrap();
+    let examples = [
+        ("g", "http://a/b/c/g"),
+        ("./g", "http://a/b/c/g"),
+        ("g/", "http://a/b/c/g/"),
+        ("/g", "http://a/g"),
+        ("//g", "http://g/"),
+        ("?y", "http://a/b/c/d;p?y"),
+        ("g?y", "http://a/b/c/g?y"),
+        ("#s", "http://a/b/c/d;p?q#s"),
+        ("g#s", "http://a/b/c/g#s"),
+        ("g?y#s", "http://a/b/c/g?y#s"),
+        (";x", "http://a/b/c/;x"),
+        ("g;x", "http://a/b/c/g;x"),
+        ("g;x?y#s", "http://a/b/c/g;x?y#s"),
+        ("", "http://a/b/c/d;p?q"),
+        (".", "http://a/b/c/"),
+        ("./", "http://a/b/c/"),
+        ("..", "http://a/b/"),
+        ("../", "http://a/b/"),
+        ("../g", "http://a/b/g"),
+        ("../..", "http://a/"),
+        ("../../", "http://a/"),
+        ("../../g", "http://a/g"),
+        ("../../../g", "http://a/g"),
+        ("../../../../g", "http://a/g"),
+        ("/./g", "http://a/g"),
+        ("/../g", "http://a/g"),
+        ("g.", "http://a/b/c/g."),
+        (".g", "http://a/b/c/.g"),
+        ("g..", "http://a/b/c/g.."),
+        ("..g", "http://a/b/c/..g"),
+        ("./../g", "http://a/b/g"),
+        ("./g/.", "http://a/b/c/g/"),
+        ("g/./h", "http://a/b/c/g/h"),
+        ("g/../h", "http://a/b/c/h"),
+        ("g;x=1/./y", "http://a/b/c/g;x=1/y"),
+        ("g;x=1/../y", "http://a/b/c/y"),
+        ("g?y/./x", "http://a/b/c/g?y/./x"),
+        ("g?y/../x", "http://a/b/c/g?y/../x"),
+        ("g#s/./x", "http://a/b/c/g#s/./x"),
+        ("g#s/../x", "http://a/b/c/g#s/../x"),
+        ("https://e/f", "https://e/f"),
+    ];
+
+    for (reference, expected) in examples {
+        let joined = base.join(reference).unwrap();
+        assert_eq!(joined.to_string(), expected, "{reference}");
+    }
+}
+
+#[test]
+fn follows_scheme_relative_locations_in_the_scheme_of_the_base() {
+    for scheme in ["http", "https"] {
+        let base = Url::new(&format!("{scheme}://a/b/c")).unwrap();
+        let follower = base
+            .follow(String::from("//other.example:8080/path?q"))
+            .unwrap();
+        assert_eq!(follower.scheme(), scheme);
+        assert_eq!(
+            follower.to_string(),
+            format!("{scheme}://other.example:8080/path?q")
+        );
+    }
+}
+
+#[test]
+fn follows_only_from_network_urls() {
+    for base in ["data:,x", "file:///tmp/a.html", "about:blank"] {
+        let base = Url::new(base).unwrap();
+        assert!(base.follow(String::from("http://example.com/")).is_err());
+    }
+}
+
+#[test]
+fn handles_local_urls_without_panicking() {
+    let mut ctx = RequestContext::default();
+    for url in ["file:///dev/null", "data:,hi", "about:blank"] {
+        let url = Url::new(url).unwrap();
+        let error = url.build_request(&RequestOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{url}");
+        assert_eq!(url.display_path(), None, "{url}");
+
+        let response = url.request(&mut ctx, &RequestOptions::default()).unwrap();
+        assert!(response.text().is_ok(), "{url}");
+    }
+}
+
+#[test]
+fn rejects_urls_over_the_maximum_length() {
+    let url = format!("http://example.com/{}", "a".repeat(Url::DEFAULT_MAX_LENGTH));
+    assert_eq!(
+        Url::new(&url),
+        Err(UrlParseError::TooLong {
+            length: url.len(),
+            max_length: Url::DEFAULT_MAX_LENGTH,
+        })
+    );
+    assert!(Url::with_max_length(&url, url.len()).is_ok());
+    assert!(Url::with_max_length("http://example.com/", 10).is_err());
+}
+
+#[test]
+fn takes_an_empty_port_as_the_default() {
+    let url = Url::new("http://example.com:/path").unwrap();
+    assert!(matches!(url, Url::Http { addr, .. } if addr == ("example.com".to_string(), 80)));
+}
+
+#[test]
+fn connects_to_the_default_port_of_each_scheme() {
+    let defaults = [
+        ("http://example.com/", 80),
+        ("https://example.com/", 443),
+        ("gemini://example.com/", 1965),
+    ];
+
+    for (url, port) in defaults {
+        let addr = match Url::new(url).unwrap() {
+            Url::Http { addr, .. } | Url::Https { addr, .. } | Url::Gemini { addr, .. } => addr,
+            url => panic!("{url} is not a network URL"),
+        };
+        assert_eq!(addr, ("example.com".to_string(), port), "{url}");
+    }
+    assert_eq!(Url::default_port("file"), None);
+}
+
+#[test]
+fn rejects_unfetchable_and_unknown_schemes() {
+    assert_eq!(
+        Url::new("mailto:someone@example.com"),
+        Err(UrlParseError::NotFetchable(String::from("mailto")))
+    );
+    assert_eq!(
+        Url::new("ftp://example.com/file"),
+        Err(UrlParseError::UnsupportedScheme(String::from("ftp")))
+    );
+    assert_eq!(
+        Url::new("example.com/path"),
+        Err(UrlParseError::MissingScheme)
+    );
+}
+
+#[test]
+fn requires_a_comma_in_data_urls() {
+    let mut ctx = RequestContext::default();
+    let url = Url::new("data:,hello").unwrap();
+    let response = url.request(&mut ctx, &RequestOptions::default()).unwrap();
+    assert_eq!(response.text().unwrap(), "hello");
+
+    assert_eq!(
+        Url::new("data:nocomma"),
+        Err(UrlParseError::MalformedDataUrl)
+    );
+}
+
+#[test]
+fn percent_encodes_unsafe_characters_once() {
+    let targets = [
+        ("http://example.com/a b", "/a%20b"),
+        ("http://example.com/a%20b", "/a%20b"),
+        ("http://example.com/a%2", "/a%252"),
+    ];
+
+    for (url, target) in targets {
+        let request = Url::new(url)
+            .unwrap()
+            .build_request(&RequestOptions::default())
+            .unwrap();
+        assert!(
+            request.starts_with(&format!("GET {target} HTTP/1.1\r\n")),
+            "{url}: {request}"
+        );
+    }
+}
+
+#[test]
+fn keeps_fragments_off_the_wire_and_out_of_local_content() {
+    let url = Url::new("http://example.com/a?q=1#top").unwrap();
+    assert_eq!(url.fragment(), Some("top"));
+    let request = url.build_request(&RequestOptions::default()).unwrap();
+    assert!(request.starts_with("GET /a?q=1 HTTP/1.1\r\n"), "{request}");
+    assert!(!request.contains("top"));
+
+    let path = env::temp_dir().join(format!("vanadium-fragment-{}", process::id()));
+    fs::write(&path, "file").unwrap();
+    let mut ctx = RequestContext::default();
+    for (url, content) in [
+        (format!("file://{}#top", path.display()), "file"),
+        (String::from("data:,data#top"), "data"),
+    ] {
+        let url = Url::new(&url).unwrap();
+        assert_eq!(url.fragment(), Some("top"));
+        let response = url.request(&mut ctx, &RequestOptions::default()).unwrap();
+        assert_eq!(response.text().unwrap(), content, "{url}");
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn encodes_internationalized_hosts_as_punycode() {
+    for url in ["http://münchen.de/", "http://M%C3%BCnchen.de/"] {
+        let url = Url::new(url).unwrap();
+        assert!(
+            matches!(&url, Url::Http { addr, .. } if addr.0 == "xn--mnchen-3ya.de"),
+            "{url:?}"
+        );
+        let request = url.build_request(&RequestOptions::default()).unwrap();
+        assert!(
+            request.contains("\r\nHost: xn--mnchen-3ya.de\r\n"),
+            "{request}"
+        );
+    }
+}